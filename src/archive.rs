@@ -0,0 +1,152 @@
+use crate::errors::BackupResult;
+use crate::options::Options as CLIopts;
+use crate::s3::{RemoteObject, S3Client};
+
+use log::{error, info, warn};
+use std::time::Duration;
+
+/// Objects in these classes have to be restored from the archive before they can be downloaded,
+/// unlike GLACIER_IR which allows immediate retrieval
+const ARCHIVED_STORAGE_CLASSES: [&str; 2] = ["GLACIER", "DEEP_ARCHIVE"];
+const POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+enum ArchiveState {
+    Available,
+    Pending,
+}
+
+pub fn is_archived(object: &RemoteObject) -> bool {
+    object
+        .storage_class
+        .as_deref()
+        .is_some_and(is_archived_class)
+}
+
+pub fn is_archived_class(storage_class: &str) -> bool {
+    ARCHIVED_STORAGE_CLASSES.contains(&storage_class)
+}
+
+/// Interprets the `x-amz-restore` header, which is absent until a restore has been requested
+fn retrieval_state(restore: Option<&str>) -> Option<ArchiveState> {
+    match restore {
+        Some(restore) if restore.contains("ongoing-request=\"false\"") => {
+            Some(ArchiveState::Available)
+        }
+        Some(_) => Some(ArchiveState::Pending),
+        None => None,
+    }
+}
+
+/// Requests a temporary copy of an archived object unless one is already available or on its way
+async fn request_retrieval(
+    client: &S3Client,
+    key: &str,
+    days: i32,
+    tier: &str,
+) -> BackupResult<ArchiveState> {
+    let head = client.head_file(key).await?;
+    if let Some(state) = retrieval_state(head.restore()) {
+        return Ok(state);
+    }
+
+    info!("Requesting retrieval of archived object {}", key);
+    client.restore_archived(key, days, tier).await?;
+    Ok(ArchiveState::Pending)
+}
+
+/// Polls the pending objects until all of them can be downloaded, returning the keys which failed
+async fn wait_for_retrieval(client: &S3Client, mut pending: Vec<String>) -> Vec<String> {
+    let mut failed = Vec::new();
+
+    while !pending.is_empty() {
+        info!(
+            "Waiting for {} archived objects to be retrieved",
+            pending.len()
+        );
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let mut still_pending = Vec::new();
+        for key in pending {
+            match client.head_file(&key).await {
+                Ok(head) if retrieval_state(head.restore()) == Some(ArchiveState::Available) => {}
+                Ok(_) => still_pending.push(key),
+                Err(err) => {
+                    error!("Failed to check the retrieval of {}: {}", key, err);
+                    failed.push(key);
+                }
+            }
+        }
+        pending = still_pending;
+    }
+
+    failed
+}
+
+/// Requests the retrieval of every archived object, waiting for them with --wait-for-archive.
+/// Returns the keys which are still being retrieved and the ones which couldn't be retrieved.
+pub async fn retrieve_all(
+    client: &S3Client,
+    objects: &[RemoteObject],
+    args: &CLIopts,
+) -> (Vec<String>, Vec<String>) {
+    let mut pending = Vec::new();
+    let mut failed = Vec::new();
+
+    for object in objects.iter().filter(|object| is_archived(object)) {
+        match request_retrieval(client, &object.key, args.restore_days, &args.restore_tier).await {
+            Ok(ArchiveState::Available) => {}
+            Ok(ArchiveState::Pending) => pending.push(object.key.clone()),
+            Err(err) => {
+                error!(
+                    "Failed to retrieve {} from the archive: {}",
+                    object.key, err
+                );
+                failed.push(object.key.clone());
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        if args.wait_for_archive {
+            failed.extend(wait_for_retrieval(client, std::mem::take(&mut pending)).await);
+        } else {
+            warn!(
+                "{} archived objects are being retrieved with the {} tier; run again once \
+                they're available or pass --wait-for-archive",
+                pending.len(),
+                args.restore_tier
+            );
+        }
+    }
+
+    (pending, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_glacier_and_deep_archive_are_archived() {
+        assert!(is_archived_class("GLACIER"));
+        assert!(is_archived_class("DEEP_ARCHIVE"));
+        assert!(!is_archived_class("GLACIER_IR"));
+        assert!(!is_archived_class("STANDARD"));
+    }
+
+    #[test]
+    fn restore_header_tells_whether_the_copy_is_available() {
+        assert_eq!(retrieval_state(None), None);
+        assert_eq!(
+            retrieval_state(Some("ongoing-request=\"true\"")),
+            Some(ArchiveState::Pending)
+        );
+        assert_eq!(
+            retrieval_state(Some(
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\""
+            )),
+            Some(ArchiveState::Available)
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::errors::BackupResult;
+use crate::s3::S3Client;
+
+use aws_sdk_s3::types::ByteStream;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+
+const LOWERCASE_PROBE_KEY: &str = ".backup-rs-case-probe";
+const UPPERCASE_PROBE_KEY: &str = ".BACKUP-RS-CASE-PROBE";
+
+/// Tracks which keys are taken on a case-insensitive store, where keys which only differ in case
+/// overwrite each other
+#[derive(Debug, Default)]
+pub struct FoldedKeys {
+    /// The keys of the objects in the bucket, by their lowercase form
+    remote: HashMap<String, String>,
+    /// The lowercased keys used so far during this run
+    claimed: HashSet<String>,
+}
+
+impl FoldedKeys {
+    pub fn new<'a>(remote_keys: impl IntoIterator<Item = &'a str>) -> FoldedKeys {
+        FoldedKeys {
+            remote: remote_keys
+                .into_iter()
+                .map(|key| (key.to_lowercase(), key.to_owned()))
+                .collect(),
+            claimed: HashSet::new(),
+        }
+    }
+
+    /// Claims the key for a file, unless another file of this run or an object in the bucket with
+    /// a different case already uses it
+    pub fn claim(&mut self, key: &str) -> bool {
+        let folded = key.to_lowercase();
+        if self.claimed.contains(&folded) {
+            return false;
+        }
+        if self
+            .remote
+            .get(&folded)
+            .is_some_and(|remote_key| remote_key != key)
+        {
+            return false;
+        }
+
+        self.claimed.insert(folded)
+    }
+}
+
+/// Uploads two probes whose keys only differ in case, with different sizes. On a case-insensitive
+/// store the second one overwrites the first, which shows in the size of the lowercase key.
+pub async fn is_case_insensitive(client: &S3Client) -> BackupResult<bool> {
+    client
+        .upload_file(
+            ByteStream::from_static(b"a"),
+            LOWERCASE_PROBE_KEY,
+            HashMap::new(),
+        )
+        .await?;
+    client
+        .upload_file(
+            ByteStream::from_static(b"AA"),
+            UPPERCASE_PROBE_KEY,
+            HashMap::new(),
+        )
+        .await?;
+
+    let head = client.head_file(LOWERCASE_PROBE_KEY).await;
+
+    for key in [LOWERCASE_PROBE_KEY, UPPERCASE_PROBE_KEY] {
+        if let Err(err) = client.delete_file(key).await {
+            warn!("Unable to remove the case probe {}: {}", key, err);
+        }
+    }
+
+    Ok(head?.content_length() == 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_differing_in_case_are_claimed_once() {
+        let mut folded_keys = FoldedKeys::default();
+        assert!(folded_keys.claim("dir/Readme.md"));
+        assert!(!folded_keys.claim("dir/README.md"));
+        assert!(!folded_keys.claim("dir/Readme.md"));
+    }
+
+    #[test]
+    fn remote_keys_are_only_claimed_in_the_same_case() {
+        let mut folded_keys = FoldedKeys::new(["dir/README.md", "dir/a~1.txt"]);
+        assert!(!folded_keys.claim("dir/readme.md"));
+        assert!(folded_keys.claim("dir/README.md"));
+        assert!(folded_keys.claim("dir/a~1.txt"));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::errors::BackupResult;
+use crate::etag;
+use crate::metadata::{file_mode, hash_file, FileMetadata};
+use crate::s3::{ObjectAttributes, RemoteObject, S3Client};
+
+use log::warn;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// How a local file differs from the object that was previously uploaded for it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Unchanged,
+    Content,
+    /// The content is the same but the stored metadata is outdated, so the object is copied onto
+    /// itself with these attributes
+    Metadata(ObjectAttributes),
+}
+
+/// Hashes files of at least `checksum_over` bytes and compares them to the hash stored on upload,
+/// falling back to the size and modification time for smaller files or objects without a hash.
+/// When `compare_metadata` is set, unchanged files are also checked for outdated metadata.
+pub async fn detect_change(
+    client: &S3Client,
+    path: &Path,
+    metadata: &Metadata,
+    remote: &RemoteObject,
+    checksum_over: Option<u64>,
+    compare_metadata: bool,
+) -> Change {
+    let hash_content = checksum_over.is_some_and(|threshold| metadata.len() >= threshold);
+
+    let current = if hash_content || compare_metadata {
+        match client.head_file(&remote.key).await {
+            Ok(head) => Some(ObjectAttributes::from_head(&head)),
+            Err(err) => {
+                warn!("Unable to retrieve the metadata of {}: {}", remote.key, err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    compare(
+        path,
+        metadata,
+        remote,
+        current,
+        checksum_over,
+        compare_metadata,
+    )
+}
+
+/// Compares the file against the metadata which was stored on upload, if it was retrieved
+fn compare(
+    path: &Path,
+    metadata: &Metadata,
+    remote: &RemoteObject,
+    current: Option<ObjectAttributes>,
+    checksum_over: Option<u64>,
+    compare_metadata: bool,
+) -> Change {
+    let stored = current
+        .as_ref()
+        .and_then(|current| FileMetadata::from_object(&current.metadata));
+    let hash_content = checksum_over.is_some_and(|threshold| metadata.len() >= threshold);
+    let content_changed = match (checksum_over, &stored) {
+        (None, _) => false,
+        (Some(_), Some(stored)) if hash_content => match hash_file(path) {
+            Ok(hash) => hash != stored.sha256,
+            Err(err) => {
+                warn!("Unable to hash {:?}: {}", path, err);
+                modified_since_upload(metadata, remote)
+            }
+        },
+        (Some(_), _) => modified_since_upload(metadata, remote),
+    };
+
+    if content_changed {
+        return Change::Content;
+    }
+
+    match stored {
+        // A different size means the content changed, even when it wasn't otherwise compared
+        Some(stored) if compare_metadata && stored.size != metadata.len() => Change::Content,
+        Some(stored) if compare_metadata => {
+            let updated = FileMetadata {
+                size: metadata.len(),
+                sha256: stored.sha256.clone(),
+                mode: file_mode(metadata),
+            };
+
+            match current {
+                Some(current) if updated != stored => {
+                    Change::Metadata(current.with_metadata(&updated))
+                }
+                _ => Change::Unchanged,
+            }
+        }
+        _ => Change::Unchanged,
+    }
+}
+
+/// Adds our metadata to an unchanged object which was uploaded without it, e.g. by an older
+/// version, returning whether the object was updated
+pub async fn backfill_metadata(
+    client: &S3Client,
+    path: &Path,
+    remote: &RemoteObject,
+) -> BackupResult<bool> {
+    let head = client.head_file(&remote.key).await?;
+    let file_metadata = match missing_metadata(path, head.metadata()) {
+        Some(file_metadata) => file_metadata,
+        None => return Ok(false),
+    };
+
+    let attributes = ObjectAttributes::from_head(&head).with_metadata(&file_metadata);
+    client.update_metadata(&remote.key, &attributes).await?;
+    Ok(true)
+}
+
+/// The metadata to add to an object which has none of ours, when the file can be read
+fn missing_metadata(
+    path: &Path,
+    object_metadata: Option<&HashMap<String, String>>,
+) -> Option<FileMetadata> {
+    if object_metadata
+        .and_then(FileMetadata::from_object)
+        .is_some()
+    {
+        return None;
+    }
+
+    match FileMetadata::from_file(path) {
+        Ok(file_metadata) => Some(file_metadata),
+        Err(err) => {
+            warn!("Unable to read the metadata of {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Compares the file to the remote ETag, or returns `None` when there's nothing to compare against
+pub fn etag_changed(
+    path: &Path,
+    metadata: &Metadata,
+    remote: &RemoteObject,
+    part_size: Option<u64>,
+) -> Option<bool> {
+    let e_tag = remote.e_tag.as_deref()?;
+    match etag::matches(path, metadata.len(), e_tag, part_size) {
+        Ok(matches) => Some(!matches),
+        Err(err) => {
+            warn!("Unable to compute the ETag of {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
+pub fn modified_since_upload(metadata: &Metadata, remote: &RemoteObject) -> bool {
+    if metadata.len() as i64 != remote.size {
+        return true;
+    }
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64);
+
+    match (modified, remote.last_modified) {
+        (Some(local), Some(uploaded)) => local > uploaded,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::ORIGINAL_PATH_KEY;
+    use aws_sdk_s3::model::StorageClass;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-changes-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// An object uploaded long after the file was last modified
+    fn remote_object(size: i64) -> RemoteObject {
+        RemoteObject {
+            key: "key".to_owned(),
+            size,
+            last_modified: Some(i64::MAX),
+            e_tag: None,
+            storage_class: None,
+        }
+    }
+
+    fn stored(size: u64, sha256: &str) -> Option<ObjectAttributes> {
+        let file_metadata = FileMetadata {
+            size,
+            sha256: sha256.to_owned(),
+            mode: None,
+        };
+        Some(ObjectAttributes::default().with_metadata(&file_metadata))
+    }
+
+    #[test]
+    fn large_files_are_compared_by_hash() {
+        let path = temp_file("large", b"new content");
+        let metadata = fs::metadata(&path).unwrap();
+        let remote = remote_object(11);
+
+        let change = compare(&path, &metadata, &remote, stored(11, "old"), Some(4), false);
+        assert_eq!(change, Change::Content);
+
+        let hash = hash_file(&path).unwrap();
+        let change = compare(&path, &metadata, &remote, stored(11, &hash), Some(4), false);
+        assert_eq!(change, Change::Unchanged);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn small_files_are_compared_by_size_and_modification_time() {
+        let path = temp_file("small", b"new content");
+        let metadata = fs::metadata(&path).unwrap();
+
+        let change = compare(&path, &metadata, &remote_object(11), None, Some(64), false);
+        assert_eq!(change, Change::Unchanged);
+
+        let change = compare(&path, &metadata, &remote_object(3), None, Some(64), false);
+        assert_eq!(change, Change::Content);
+
+        let uploaded_before_modification = RemoteObject {
+            last_modified: Some(0),
+            ..remote_object(11)
+        };
+        assert!(modified_since_upload(
+            &metadata,
+            &uploaded_before_modification
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn outdated_mode_is_a_metadata_change() {
+        let path = temp_file("mode", b"content");
+        let metadata = fs::metadata(&path).unwrap();
+        let hash = hash_file(&path).unwrap();
+        let remote = remote_object(7);
+        let stored_mode = FileMetadata {
+            size: 7,
+            sha256: hash.clone(),
+            mode: Some(0o4000),
+        };
+        let mut current = ObjectAttributes {
+            storage_class: Some(StorageClass::StandardIa),
+            sha256_checksum: true,
+            ..ObjectAttributes::default()
+        }
+        .with_metadata(&stored_mode);
+        current
+            .metadata
+            .insert(ORIGINAL_PATH_KEY.to_owned(), "mode ".to_owned());
+
+        let updated = FileMetadata {
+            size: 7,
+            sha256: hash,
+            mode: file_mode(&metadata),
+        };
+        match compare(&path, &metadata, &remote, Some(current), None, true) {
+            Change::Metadata(attributes) => {
+                assert_eq!(
+                    FileMetadata::from_object(&attributes.metadata),
+                    Some(updated)
+                );
+                assert_eq!(attributes.metadata[ORIGINAL_PATH_KEY], "mode ");
+                assert_eq!(attributes.storage_class, Some(StorageClass::StandardIa));
+                assert!(attributes.sha256_checksum);
+            }
+            change => panic!("expected a metadata change, got {:?}", change),
+        }
+
+        let change = compare(&path, &metadata, &remote, stored(3, "old"), None, true);
+        assert_eq!(change, Change::Content);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_objects_without_our_metadata_are_backfilled() {
+        let path = temp_file("backfill", b"content");
+        let file_metadata = FileMetadata::from_file(&path).unwrap();
+
+        assert_eq!(
+            missing_metadata(&path, Some(&HashMap::new())),
+            Some(file_metadata)
+        );
+        let stored = FileMetadata::from_file(&path).unwrap().to_object();
+        assert_eq!(missing_metadata(&path, Some(&stored)), None);
+        assert_eq!(
+            missing_metadata(&path.with_extension("missing"), None),
+            None
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use crate::key_template::KeyTemplate;
+use crate::s3::RemoteObject;
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CheckpointHeader {
+    bucket: String,
+    root: PathBuf,
+    #[serde(default)]
+    prefix: Option<String>,
+    /// The key template with the host and date of the run filled in
+    #[serde(default)]
+    key_template: Option<String>,
+}
+
+impl CheckpointHeader {
+    fn new(
+        bucket: &str,
+        root: &Path,
+        prefix: Option<&str>,
+        key_template: Option<&KeyTemplate>,
+    ) -> CheckpointHeader {
+        CheckpointHeader {
+            bucket: bucket.to_owned(),
+            root: root.to_owned(),
+            prefix: prefix.map(str::to_owned),
+            key_template: key_template.map(KeyTemplate::to_string),
+        }
+    }
+}
+
+/// Records every file that's known to be backed up so an interrupted run can resume without listing
+/// the bucket. The first line identifies the bucket, root and key layout, followed by one line per
+/// object.
+pub struct Checkpoint {
+    path: PathBuf,
+    file: File,
+}
+
+impl Checkpoint {
+    pub fn create(
+        directory: &Path,
+        bucket: &str,
+        root: &Path,
+        prefix: Option<&str>,
+        key_template: Option<&KeyTemplate>,
+    ) -> io::Result<Checkpoint> {
+        fs::create_dir_all(directory)?;
+        let path = checkpoint_path(directory, bucket);
+        let mut file = File::create(&path)?;
+
+        let header = CheckpointHeader::new(bucket, root, prefix, key_template);
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+
+        Ok(Checkpoint { path, file })
+    }
+
+    /// Reads back the objects of a previous run, refusing checkpoints for a different bucket or root,
+    /// or whose keys were built differently
+    pub fn resume(
+        directory: &Path,
+        bucket: &str,
+        root: &Path,
+        prefix: Option<&str>,
+        key_template: Option<&KeyTemplate>,
+    ) -> io::Result<(Checkpoint, Vec<RemoteObject>)> {
+        let path = checkpoint_path(directory, bucket);
+        let mut lines = BufReader::new(File::open(&path)?).lines();
+
+        let header: CheckpointHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "empty checkpoint",
+                ))
+            }
+        };
+
+        if header.bucket != bucket || header.root != root {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "checkpoint was made for {:?} in bucket {}",
+                    header.root, header.bucket
+                ),
+            ));
+        }
+
+        if header != CheckpointHeader::new(bucket, root, prefix, key_template) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "checkpoint was made for keys with prefix {:?} and template {:?}",
+                    header.prefix, header.key_template
+                ),
+            ));
+        }
+
+        // An interrupted write can leave a partial last line behind, which is simply not trusted
+        let objects = lines
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .collect();
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok((Checkpoint { path, file }, objects))
+    }
+
+    pub fn record(&mut self, object: &RemoteObject) -> io::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(object)?)
+    }
+
+    /// Makes sure everything recorded so far has reached the disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// A completed run leaves nothing to resume so the checkpoint is removed
+    pub fn complete(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(self.path)
+    }
+}
+
+fn checkpoint_path(directory: &Path, bucket: &str) -> PathBuf {
+    directory.join(format!("{}.checkpoint.jsonl", bucket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "backup-rs-checkpoint-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn object(key: &str) -> RemoteObject {
+        RemoteObject {
+            key: key.to_owned(),
+            size: 1,
+            last_modified: None,
+            e_tag: None,
+            storage_class: None,
+        }
+    }
+
+    #[test]
+    fn resume_reads_back_the_recorded_objects() {
+        let directory = temp_directory("resume");
+        let root = Path::new("/home/user");
+        let mut checkpoint =
+            Checkpoint::create(&directory, "bucket", root, Some("laptop"), None).unwrap();
+        checkpoint.record(&object("laptop/a")).unwrap();
+        drop(checkpoint);
+
+        let (_, objects) =
+            Checkpoint::resume(&directory, "bucket", root, Some("laptop"), None).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "laptop/a");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn resume_refuses_a_different_prefix_or_template() {
+        let directory = temp_directory("prefix");
+        let root = Path::new("/home/user");
+        Checkpoint::create(&directory, "bucket", root, Some("laptop"), None).unwrap();
+
+        assert!(Checkpoint::resume(&directory, "bucket", root, Some("desktop"), None).is_err());
+        let template = KeyTemplate::parse("{prefix}/{relpath}").unwrap();
+        assert!(
+            Checkpoint::resume(&directory, "bucket", root, Some("laptop"), Some(&template))
+                .is_err()
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn partially_written_last_line_is_ignored() {
+        let directory = temp_directory("partial");
+        let root = Path::new("/home/user");
+        let mut checkpoint = Checkpoint::create(&directory, "bucket", root, None, None).unwrap();
+        checkpoint.record(&object("a")).unwrap();
+        write!(checkpoint.file, "{{\"key\":\"b\",\"si").unwrap();
+        drop(checkpoint);
+
+        let (_, objects) = Checkpoint::resume(&directory, "bucket", root, None, None).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "a");
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn completing_removes_the_checkpoint() {
+        let directory = temp_directory("complete");
+        let root = Path::new("/home/user");
+        let checkpoint = Checkpoint::create(&directory, "bucket", root, None, None).unwrap();
+        checkpoint.complete().unwrap();
+
+        assert!(Checkpoint::resume(&directory, "bucket", root, None, None).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes `SHA256SUMS`-style lines which `sha256sum -c` can verify from the restored root
+pub fn write_checksums(
+    path: &Path,
+    checksums: &[(String, String)],
+    append: bool,
+) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let mut writer = BufWriter::new(file);
+
+    for (file_path, hash) in checksums {
+        writeln!(writer, "{}", format_line(hash, file_path))?;
+    }
+
+    writer.flush()
+}
+
+/// Mirrors sha256sum, which escapes backslashes and newlines and marks such lines with a leading backslash
+pub fn format_line(hash: &str, file_path: &str) -> String {
+    if file_path.contains(&['\\', '\n'][..]) {
+        let escaped = file_path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", hash, escaped)
+    } else {
+        format!("{}  {}", hash, file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn special_characters_are_escaped_like_sha256sum() {
+        assert_eq!(format_line("abc", "dir/a.txt"), "abc  dir/a.txt");
+        assert_eq!(format_line("abc", "a\\b\nc"), "\\abc  a\\\\b\\nc");
+    }
+
+    #[test]
+    fn appending_keeps_the_earlier_checksums() {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-checksums-append-{}", std::process::id()));
+        let checksums = |file_path: &str| vec![(file_path.to_owned(), "abc".to_owned())];
+
+        write_checksums(&path, &checksums("a"), false).unwrap();
+        write_checksums(&path, &checksums("b"), true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc  a\nabc  b\n");
+
+        write_checksums(&path, &checksums("c"), false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc  c\n");
+        fs::remove_file(path).unwrap();
+    }
+}
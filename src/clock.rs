@@ -0,0 +1,68 @@
+use crate::options::ClockSkewPolicy;
+use crate::s3::S3Client;
+
+use log::{error, warn};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether the run may continue given the difference between the local and the server's clock
+pub fn skew_acceptable(local: i64, server: i64, max_skew: u64, policy: ClockSkewPolicy) -> bool {
+    let skew = local - server;
+    if skew.unsigned_abs() <= max_skew {
+        return true;
+    }
+
+    let direction = if skew > 0 { "ahead of" } else { "behind" };
+    match policy {
+        ClockSkewPolicy::Warn => {
+            warn!(
+                "The local clock is {}s {} the server's clock, which makes change detection unreliable",
+                skew.unsigned_abs(),
+                direction
+            );
+            true
+        }
+        ClockSkewPolicy::Abort => {
+            error!(
+                "The local clock is {}s {} the server's clock, aborting",
+                skew.unsigned_abs(),
+                direction
+            );
+            false
+        }
+    }
+}
+
+/// Compares the local clock to the server's, proceeding when the server's time can't be determined
+pub async fn check(client: &S3Client, max_skew: u64, policy: ClockSkewPolicy) -> bool {
+    let server = match client.server_time().await {
+        Some(server) => server,
+        None => {
+            warn!("Unable to determine the server's time, skipping the clock skew check");
+            return true;
+        }
+    };
+
+    let local = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+
+    skew_acceptable(local, server, max_skew, policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_skew_only_aborts_when_asked_to() {
+        assert!(!skew_acceptable(10_000, 0, 300, ClockSkewPolicy::Abort));
+        assert!(!skew_acceptable(0, 10_000, 300, ClockSkewPolicy::Abort));
+        assert!(skew_acceptable(10_000, 0, 300, ClockSkewPolicy::Warn));
+    }
+
+    #[test]
+    fn skew_within_the_threshold_is_acceptable() {
+        assert!(skew_acceptable(1_300, 1_000, 300, ClockSkewPolicy::Abort));
+        assert!(skew_acceptable(700, 1_000, 300, ClockSkewPolicy::Abort));
+    }
+}
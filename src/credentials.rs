@@ -0,0 +1,148 @@
+use crate::errors::{BackupError, BackupResult};
+
+use aws_credential_types::cache::CredentialsCache;
+use aws_credential_types::provider::ProvideCredentials;
+use aws_credential_types::time_source::TimeSource;
+use aws_sdk_s3::Region;
+use log::{info, warn};
+use std::time::{Duration, SystemTime};
+
+/// How long before they expire temporary credentials are refreshed, so a slow upload which starts
+/// just before the expiry doesn't get rejected halfway
+const REFRESH_BUFFER: Duration = Duration::from_secs(5 * 60);
+
+/// Caches the credentials until they're about to expire, after which the next request loads them
+/// again from the provider
+pub fn refreshing_cache(time_source: TimeSource) -> CredentialsCache {
+    CredentialsCache::lazy_builder()
+        .buffer_time(REFRESH_BUFFER)
+        .time_source(time_source)
+        .into_credentials_cache()
+}
+
+/// Resolves the credentials up front so missing or unusable ones fail the run before it starts, and
+/// warns when they expire within the expected run time. Credentials from profiles, SSO or
+/// assume-role are refreshed automatically, but static session credentials such as those in
+/// `AWS_SESSION_TOKEN` can't be.
+pub async fn check_expiry(region: String, expected_run_time: Duration) -> BackupResult<()> {
+    let config = aws_config::from_env()
+        .region(Region::new(region))
+        .load()
+        .await;
+    let provider = config.credentials_provider().ok_or_else(|| {
+        BackupError::CredentialsUnavailable("no credentials provider configured".to_owned())
+    })?;
+
+    let credentials = provider
+        .provide_credentials()
+        .await
+        .map_err(|err| BackupError::CredentialsUnavailable(err.to_string()))?;
+
+    let expiry = match credentials.expiry() {
+        Some(expiry) => expiry,
+        None => {
+            info!("The credentials don't expire");
+            return Ok(());
+        }
+    };
+
+    let remaining = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+    if remaining < expected_run_time {
+        warn!(
+            "The credentials expire in {} minutes, sooner than the expected run time of {} minutes. \
+            Uploads will fail if they can't be refreshed.",
+            remaining.as_secs() / 60,
+            expected_run_time.as_secs() / 60
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_credential_types::cache::ProvideCachedCredentials;
+    use aws_credential_types::provider::{
+        error::CredentialsError, future, SharedCredentialsProvider,
+    };
+    use aws_credential_types::time_source::TestingTimeSource;
+    use aws_credential_types::Credentials;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    const LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+    /// Hands out credentials which expire an hour after they're loaded, until it's told to fail
+    #[derive(Debug)]
+    struct TemporaryCredentials {
+        time: TestingTimeSource,
+        loads: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl ProvideCredentials for TemporaryCredentials {
+        fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+        where
+            Self: 'a,
+        {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                return future::ProvideCredentials::ready(Err(CredentialsError::provider_error(
+                    "session expired",
+                )));
+            }
+
+            let expiry = self.time.now() + LIFETIME;
+            future::ProvideCredentials::ready(Ok(Credentials::new(
+                "key",
+                "secret",
+                Some("token".to_owned()),
+                Some(expiry),
+                "test",
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials_are_refreshed_before_they_expire() {
+        let mut time = TestingTimeSource::new(UNIX_EPOCH);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let provider = TemporaryCredentials {
+            time: time.clone(),
+            loads: loads.clone(),
+            fail: false,
+        };
+        let cache = refreshing_cache(TimeSource::testing(&time))
+            .create_cache(SharedCredentialsProvider::new(provider));
+
+        cache.provide_cached_credentials().await.unwrap();
+        time.advance(LIFETIME - REFRESH_BUFFER * 2);
+        cache.provide_cached_credentials().await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // Still valid for a few minutes, but within the buffer
+        time.advance(REFRESH_BUFFER);
+        let credentials = cache.provide_cached_credentials().await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            credentials.expiry(),
+            Some(UNIX_EPOCH + LIFETIME - REFRESH_BUFFER + LIFETIME)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_is_reported() {
+        let time = TestingTimeSource::new(UNIX_EPOCH);
+        let provider = TemporaryCredentials {
+            time: time.clone(),
+            loads: Arc::new(AtomicUsize::new(0)),
+            fail: true,
+        };
+        let cache = refreshing_cache(TimeSource::testing(&time))
+            .create_cache(SharedCredentialsProvider::new(provider));
+
+        assert!(cache.provide_cached_credentials().await.is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::errors::{BackupError, BackupResult};
+use crate::metadata::FileMetadata;
+use crate::s3::S3Client;
+
+use aws_sdk_s3::model::StorageClass;
+use aws_sdk_s3::types::ByteStream;
+use std::collections::HashMap;
+use tokio::io::AsyncReadExt;
+
+/// Reads the shared index of content hashes to the key which first stored that content. A missing
+/// index is empty, since the first run to use it creates it.
+pub async fn load(client: &S3Client, key: &str) -> BackupResult<HashMap<String, String>> {
+    let object = match client.download_file(key).await {
+        Ok(object) => object,
+        Err(err) if err.is_missing_object() => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut data = Vec::new();
+    object
+        .body
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .map_err(|err| BackupError::DedupeIndexUnreadable(err.to_string()))?;
+
+    serde_json::from_slice(&data).map_err(|err| BackupError::DedupeIndexUnreadable(err.to_string()))
+}
+
+/// Whether the object still holds the content it was indexed with, since its key may have been
+/// overwritten with other content after the entry was added
+pub async fn holds_content(client: &S3Client, key: &str, sha256: &str) -> BackupResult<bool> {
+    let head = client.head_file(key).await?;
+    Ok(head
+        .metadata()
+        .and_then(FileMetadata::from_object)
+        .is_some_and(|stored| stored.sha256 == sha256))
+}
+
+/// Stores the hashes of this run, which started from the index, in it. The index is read again
+/// first so entries which other hosts added in the meantime are kept. It's always stored as
+/// STANDARD, since the next run couldn't read it from an archived storage class.
+pub async fn save(
+    client: &S3Client,
+    key: &str,
+    hashes: HashMap<String, String>,
+    written: &HashMap<String, String>,
+) -> BackupResult<()> {
+    let mut index = load(client, key).await?;
+    merge(&mut index, hashes, written);
+
+    let data = serde_json::to_vec(&index)
+        .map_err(|err| BackupError::DedupeIndexUnreadable(err.to_string()))?;
+    let length = data.len() as i64;
+    client
+        .clone()
+        .with_storage_class(StorageClass::Standard)
+        .upload_stream(ByteStream::from(data), key, length, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds the hashes of this run to the index, dropping every entry for a key which this run wrote
+/// with other content, as that key no longer holds the content the entry was added for
+fn merge(
+    index: &mut HashMap<String, String>,
+    hashes: HashMap<String, String>,
+    written: &HashMap<String, String>,
+) {
+    let is_current = |hash: &String, key: &String| {
+        written
+            .get(key)
+            .is_none_or(|written_hash| written_hash == hash)
+    };
+    index.retain(|hash, key| is_current(hash, key));
+    index.extend(
+        hashes
+            .into_iter()
+            .filter(|(hash, key)| is_current(hash, key)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_for_overwritten_keys_are_dropped() {
+        let mut index = HashMap::from([
+            ("old".to_owned(), "overwritten.txt".to_owned()),
+            ("other-host".to_owned(), "elsewhere.txt".to_owned()),
+        ]);
+        let hashes = HashMap::from([
+            ("old".to_owned(), "overwritten.txt".to_owned()),
+            ("new".to_owned(), "overwritten.txt".to_owned()),
+        ]);
+        let written = HashMap::from([("overwritten.txt".to_owned(), "new".to_owned())]);
+
+        merge(&mut index, hashes, &written);
+
+        assert_eq!(
+            index,
+            HashMap::from([
+                ("new".to_owned(), "overwritten.txt".to_owned()),
+                ("other-host".to_owned(), "elsewhere.txt".to_owned()),
+            ])
+        );
+    }
+}
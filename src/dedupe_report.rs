@@ -0,0 +1,138 @@
+use crate::exclude::Excludes;
+use crate::metadata::hash_file;
+use crate::summary::format_bytes;
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// How much --dedupe-within-run would save, computed by hashing every file without uploading
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DedupeReport {
+    pub files: usize,
+    /// Sets of at least two files with identical content
+    pub duplicate_groups: usize,
+    /// Files which would be copied from an identical file instead of uploaded
+    pub duplicate_files: usize,
+    pub savings: u64,
+}
+
+impl DedupeReport {
+    pub fn from_hashes(files: impl IntoIterator<Item = (String, u64)>) -> DedupeReport {
+        let mut sizes_by_hash: HashMap<String, (u64, usize)> = HashMap::new();
+        let mut report = DedupeReport::default();
+
+        for (hash, size) in files {
+            report.files += 1;
+            sizes_by_hash.entry(hash).or_insert((size, 0)).1 += 1;
+        }
+
+        for (size, count) in sizes_by_hash.into_values().filter(|(_, count)| *count > 1) {
+            report.duplicate_groups += 1;
+            report.duplicate_files += count - 1;
+            report.savings += size * (count - 1) as u64;
+        }
+
+        report
+    }
+
+    pub fn log(&self) {
+        info!(
+            "Found {} groups of identical files among {} files: deduplicating would skip uploading {} files, saving {}",
+            self.duplicate_groups,
+            self.files,
+            self.duplicate_files,
+            format_bytes(self.savings as f64)
+        );
+    }
+}
+
+/// Hashes every file under the root which would be considered for upload
+pub fn hash_tree(root: &Path, excludes: &Excludes) -> Vec<(String, u64)> {
+    let mut hashes = Vec::new();
+    collect_hashes(root, root, excludes, &mut hashes);
+    hashes
+}
+
+fn collect_hashes(path: &Path, root: &Path, excludes: &Excludes, hashes: &mut Vec<(String, u64)>) {
+    let excluded = path
+        .strip_prefix(root)
+        .is_ok_and(|relative| excludes.is_excluded(relative));
+    if excluded {
+        return;
+    }
+
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(err) => {
+            warn!("Unable to read the metadata for {:?}: {}", path, err);
+            return;
+        }
+    };
+
+    if metadata.is_file() {
+        if excludes.skips_file(path) {
+            return;
+        }
+
+        match hash_file(path) {
+            Ok(hash) => hashes.push((hash, metadata.len())),
+            Err(err) => warn!("Unable to hash {:?}: {}", path, err),
+        }
+        return;
+    }
+
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                collect_hashes(&entry.path(), root, excludes, hashes);
+            }
+        }
+        Err(err) => warn!("Unable to read the directory {:?}: {}", path, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn savings_count_every_copy_after_the_first() {
+        let hashes = [
+            ("a", 10),
+            ("a", 10),
+            ("a", 10),
+            ("b", 5),
+            ("b", 5),
+            ("c", 7),
+        ];
+        let report = DedupeReport::from_hashes(hashes.map(|(hash, size)| (hash.to_owned(), size)));
+
+        assert_eq!(
+            report,
+            DedupeReport {
+                files: 6,
+                duplicate_groups: 2,
+                duplicate_files: 3,
+                savings: 25,
+            }
+        );
+    }
+
+    #[test]
+    fn excluded_files_arent_hashed() {
+        let root =
+            std::env::temp_dir().join(format!("backup-rs-dedupe-report-{}", std::process::id()));
+        fs::create_dir_all(root.join("cache")).unwrap();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        fs::write(root.join("cache/c.txt"), b"same").unwrap();
+
+        let excludes = Excludes::new(&["cache".to_owned()], None).unwrap();
+        let report = DedupeReport::from_hashes(hash_tree(&root, &excludes));
+        assert_eq!(report.files, 2);
+        assert_eq!(report.duplicate_files, 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
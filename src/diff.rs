@@ -0,0 +1,118 @@
+use crate::s3::RemoteObject;
+
+use std::collections::BTreeMap;
+
+/// The keys which differ between two buckets, each sorted by key
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ListingDiff {
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+    /// Keys present in both whose size or ETag differs
+    pub differing: Vec<String>,
+}
+
+impl ListingDiff {
+    /// ETags are only compared when both sides have one, since some providers leave them out
+    pub fn compare(
+        a: impl IntoIterator<Item = RemoteObject>,
+        b: impl IntoIterator<Item = RemoteObject>,
+    ) -> ListingDiff {
+        let a: BTreeMap<String, RemoteObject> = a
+            .into_iter()
+            .map(|object| (object.key.clone(), object))
+            .collect();
+        let mut b: BTreeMap<String, RemoteObject> = b
+            .into_iter()
+            .map(|object| (object.key.clone(), object))
+            .collect();
+
+        let mut diff = ListingDiff::default();
+        for (key, object_a) in a {
+            match b.remove(&key) {
+                None => diff.only_in_a.push(key),
+                Some(object_b) if differs(&object_a, &object_b) => diff.differing.push(key),
+                Some(_) => {}
+            }
+        }
+        diff.only_in_b = b.into_keys().collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+
+    pub fn describe(&self, bucket_a: &str, bucket_b: &str) -> String {
+        let mut lines = Vec::new();
+        lines.extend(
+            self.only_in_a
+                .iter()
+                .map(|key| format!("only in {}: {}", bucket_a, key)),
+        );
+        lines.extend(
+            self.only_in_b
+                .iter()
+                .map(|key| format!("only in {}: {}", bucket_b, key)),
+        );
+        lines.extend(self.differing.iter().map(|key| format!("differs: {}", key)));
+        lines.join("\n")
+    }
+}
+
+fn differs(a: &RemoteObject, b: &RemoteObject) -> bool {
+    if a.size != b.size {
+        return true;
+    }
+
+    match (&a.e_tag, &b.e_tag) {
+        (Some(e_tag_a), Some(e_tag_b)) => e_tag_a != e_tag_b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(key: &str, size: i64, e_tag: Option<&str>) -> RemoteObject {
+        RemoteObject {
+            key: key.to_owned(),
+            size,
+            last_modified: None,
+            e_tag: e_tag.map(str::to_owned),
+            storage_class: None,
+        }
+    }
+
+    #[test]
+    fn listings_differ_by_key_size_and_etag() {
+        let a = [
+            object("same", 1, Some("x")),
+            object("only-a", 1, None),
+            object("resized", 1, None),
+            object("edited", 1, Some("x")),
+        ];
+        let b = [
+            object("same", 1, Some("x")),
+            object("only-b", 1, None),
+            object("resized", 2, None),
+            object("edited", 1, Some("y")),
+        ];
+
+        let diff = ListingDiff::compare(a, b);
+        assert_eq!(diff.only_in_a, ["only-a"]);
+        assert_eq!(diff.only_in_b, ["only-b"]);
+        assert_eq!(diff.differing, ["edited", "resized"]);
+        assert_eq!(
+            diff.describe("a", "b"),
+            "only in a: only-a\nonly in b: only-b\ndiffers: edited\ndiffers: resized"
+        );
+    }
+
+    #[test]
+    fn missing_etag_isnt_a_difference() {
+        let diff = ListingDiff::compare([object("a", 1, Some("x"))], [object("a", 1, None)]);
+        assert!(diff.is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+use crate::errors::{BackupError, BackupResult};
+
+use log::warn;
+use std::path::Path;
+
+/// Fails once the filesystem holding `directory` has less than `minimum` bytes available, so a
+/// long run doesn't destabilize the host. Free space which can't be determined isn't held against it.
+pub fn ensure_free_space(directory: &Path, minimum: u64) -> BackupResult<()> {
+    match fs2::available_space(directory) {
+        Ok(available) if available < minimum => Err(BackupError::InsufficientFreeSpace {
+            directory: directory.to_path_buf(),
+            available,
+        }),
+        Ok(_) => Ok(()),
+        Err(err) => {
+            warn!(
+                "Unable to determine the free space of {:?}: {}",
+                directory, err
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_aborts_below_the_minimum_free_space() {
+        let directory = std::env::temp_dir();
+        assert!(ensure_free_space(&directory, 0).is_ok());
+        assert!(matches!(
+            ensure_free_space(&directory, u64::MAX),
+            Err(BackupError::InsufficientFreeSpace { .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_free_space_doesnt_abort() {
+        assert!(ensure_free_space(Path::new("/nonexistent/backup-rs"), u64::MAX).is_ok());
+    }
+}
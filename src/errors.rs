@@ -1,7 +1,17 @@
+use crate::telemetry::RetryKind;
+use aws_credential_types::provider::error::CredentialsError;
 use aws_sdk_s3::{
-    error::{ListObjectsV2Error, PutObjectError},
-    types::{SdkError},
+    error::{
+        CopyObjectError, DeleteObjectError, GetBucketLifecycleConfigurationError, GetObjectError,
+        HeadObjectError, ListMultipartUploadsError, ListObjectsV2Error, ListPartsError,
+        PutBucketIntelligentTieringConfigurationError, PutBucketLifecycleConfigurationError,
+        PutObjectError, RestoreObjectError,
+    },
+    types::SdkError,
 };
+
+use std::error::Error as _;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -16,10 +26,365 @@ pub enum BackupError {
     InvalidServerSideEncryption,
 
     #[error("S3 upload failed")]
-    UploadFailed(#[from] SdkError<PutObjectError>),
+    UploadFailed(#[source] Box<SdkError<PutObjectError>>),
 
     #[error("Failed to retrieve data from server")]
-    FileFetchFailed(#[from] SdkError<ListObjectsV2Error>),
+    FileFetchFailed(#[source] Box<SdkError<ListObjectsV2Error>>),
+
+    #[error("Failed to list incomplete multipart uploads")]
+    MultipartListFailed(#[source] Box<SdkError<ListMultipartUploadsError>>),
+
+    #[error("Failed to list the parts of a multipart upload")]
+    PartListFailed(#[source] Box<SdkError<ListPartsError>>),
+
+    #[error("S3 download failed")]
+    DownloadFailed(#[source] Box<SdkError<GetObjectError>>),
+
+    #[error("Failed to copy object")]
+    CopyFailed(#[source] Box<SdkError<CopyObjectError>>),
+
+    #[error("Failed to delete object")]
+    DeleteFailed(#[source] Box<SdkError<DeleteObjectError>>),
+
+    #[error("Failed to retrieve object metadata")]
+    HeadFailed(#[source] Box<SdkError<HeadObjectError>>),
+
+    #[error("Failed to request the retrieval of an archived object")]
+    ArchiveRestoreFailed(#[source] Box<SdkError<RestoreObjectError>>),
+
+    #[error("Failed to write restored file: {0}")]
+    RestoreFailed(std::io::Error),
+
+    #[error("Invalid exclude pattern: {0}")]
+    InvalidExcludePattern(#[from] globset::Error),
+
+    #[error("Failed to read the exclude file: {0}")]
+    ExcludeFileUnreadable(std::io::Error),
+
+    #[error("Only {available} bytes are free in {directory:?}")]
+    InsufficientFreeSpace {
+        directory: std::path::PathBuf,
+        available: u64,
+    },
+
+    #[error("Failed to list the files known to git: {0}")]
+    GitListingFailed(String),
+
+    #[error("Invalid key template: {0}")]
+    InvalidKeyTemplate(String),
+
+    #[error("Unable to compare the upload against the local file")]
+    VerifyFailed(std::io::Error),
+
+    #[error("Unable to read the deduplication index: {0}")]
+    DedupeIndexUnreadable(String),
+
+    #[error("Unable to load AWS credentials: {0}")]
+    CredentialsUnavailable(String),
+
+    #[error("Failed to configure Intelligent-Tiering")]
+    TieringConfigurationFailed(
+        #[source] Box<SdkError<PutBucketIntelligentTieringConfigurationError>>,
+    ),
+
+    #[error("Failed to fetch the lifecycle configuration")]
+    LifecycleFetchFailed(#[source] Box<SdkError<GetBucketLifecycleConfigurationError>>),
+
+    #[error("Failed to configure the lifecycle rules")]
+    LifecycleConfigurationFailed(#[source] Box<SdkError<PutBucketLifecycleConfigurationError>>),
+}
+
+impl BackupError {
+    /// Some S3-compatible providers report the listing of a brand-new bucket as missing rather than empty
+    pub fn is_empty_listing(&self) -> bool {
+        match self {
+            BackupError::FileFetchFailed(err) => match err.as_ref() {
+                SdkError::ServiceError(context) => matches!(
+                    context.err().code(),
+                    Some("NoSuchBucket") | Some("NoSuchKey")
+                ),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn is_missing_object(&self) -> bool {
+        match self {
+            BackupError::DownloadFailed(err) => match err.as_ref() {
+                SdkError::ServiceError(context) => context.err().is_no_such_key(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// S3 signals throttling with 503 SlowDown, while other providers use 429
+    pub fn retry_kind(&self) -> RetryKind {
+        let status = match self.upload_error() {
+            Some(SdkError::TimeoutError(_)) => return RetryKind::Timeout,
+            Some(SdkError::ServiceError(context)) => context.raw().http().status(),
+            Some(SdkError::ResponseError(context)) => context.raw().http().status(),
+            _ => return RetryKind::Other,
+        };
+
+        match status.as_u16() {
+            429 | 503 => RetryKind::Throttling,
+            500..=599 => RetryKind::ServerError,
+            _ => RetryKind::Other,
+        }
+    }
+
+    /// Throttling, server errors, timeouts and requests which couldn't be sent may succeed when
+    /// retried, unlike e.g. denied access or invalid requests
+    pub fn is_retryable(&self) -> bool {
+        self.retry_kind() != RetryKind::Other || self.is_connection_failure()
+    }
+
+    /// The delay a throttled response asked for in its `Retry-After` header, which is only
+    /// honoured in its number of seconds form
+    pub fn retry_after(&self) -> Option<Duration> {
+        let headers = match self.upload_error() {
+            Some(SdkError::ServiceError(context)) => context.raw().http().headers(),
+            Some(SdkError::ResponseError(context)) => context.raw().http().headers(),
+            _ => return None,
+        };
+
+        let seconds = headers
+            .get("retry-after")?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        Some(Duration::from_secs(seconds))
+    }
+
+    /// Temporary credentials which expired mid-run, or which the credentials cache couldn't refresh
+    pub fn is_expired_credentials(&self) -> bool {
+        match self.upload_error() {
+            Some(SdkError::ServiceError(context)) => context.err().code() == Some("ExpiredToken"),
+            Some(err @ SdkError::ConstructionFailure(_)) => {
+                let mut source = err.source();
+                while let Some(err) = source {
+                    if err.is::<CredentialsError>() {
+                        return true;
+                    }
+                    source = err.source();
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// The request couldn't be sent at all, e.g. because the connection was reset
+    pub fn is_connection_failure(&self) -> bool {
+        matches!(self.upload_error(), Some(SdkError::DispatchFailure(_)))
+    }
+
+    /// Continuation tokens of very long listings can expire, which is reported as an invalid argument
+    pub fn is_expired_continuation_token(&self) -> bool {
+        match self {
+            BackupError::FileFetchFailed(err) => match err.as_ref() {
+                SdkError::ServiceError(context) => {
+                    context.err().code() == Some("InvalidArgument")
+                        && context.err().message().is_some_and(|message| {
+                            message.to_lowercase().contains("continuation token")
+                        })
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn upload_error(&self) -> Option<&SdkError<PutObjectError>> {
+        match self {
+            BackupError::UploadFailed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The SDK's errors are large, so they're boxed to keep every `BackupResult` small
+macro_rules! from_sdk_error {
+    ($($error:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<SdkError<$error>> for BackupError {
+                fn from(err: SdkError<$error>) -> BackupError {
+                    BackupError::$variant(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+from_sdk_error! {
+    PutObjectError => UploadFailed,
+    ListObjectsV2Error => FileFetchFailed,
+    ListMultipartUploadsError => MultipartListFailed,
+    ListPartsError => PartListFailed,
+    GetObjectError => DownloadFailed,
+    CopyObjectError => CopyFailed,
+    DeleteObjectError => DeleteFailed,
+    HeadObjectError => HeadFailed,
+    RestoreObjectError => ArchiveRestoreFailed,
+    PutBucketIntelligentTieringConfigurationError => TieringConfigurationFailed,
+    GetBucketLifecycleConfigurationError => LifecycleFetchFailed,
+    PutBucketLifecycleConfigurationError => LifecycleConfigurationFailed,
 }
 
 pub type BackupResult<T> = Result<T, BackupError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::error::GetObjectErrorKind;
+    use aws_smithy_http::{body::SdkBody, operation, result::ConnectorError};
+
+    fn service_error<E>(err: E, status: u16) -> SdkError<E> {
+        let response = http::Response::builder()
+            .status(status)
+            .body(SdkBody::empty())
+            .unwrap();
+        SdkError::service_error(err, operation::Response::new(response))
+    }
+
+    fn listing_error(code: &str) -> BackupError {
+        let err = aws_smithy_types::Error::builder().code(code).build();
+        BackupError::from(service_error(ListObjectsV2Error::generic(err), 404))
+    }
+
+    #[test]
+    fn missing_bucket_listing_counts_as_empty() {
+        assert!(listing_error("NoSuchBucket").is_empty_listing());
+        assert!(listing_error("NoSuchKey").is_empty_listing());
+        assert!(!listing_error("AccessDenied").is_empty_listing());
+    }
+
+    #[test]
+    fn failing_to_load_credentials_counts_as_expired_credentials() {
+        let err = BackupError::from(SdkError::<PutObjectError>::construction_failure(
+            CredentialsError::provider_error("session expired"),
+        ));
+        assert!(err.is_expired_credentials());
+
+        let err = BackupError::from(SdkError::<PutObjectError>::construction_failure(
+            "invalid header",
+        ));
+        assert!(!err.is_expired_credentials());
+    }
+
+    #[test]
+    fn invalid_continuation_token_counts_as_expired() {
+        let invalid_argument = |message: &str| {
+            let err = aws_smithy_types::Error::builder()
+                .code("InvalidArgument")
+                .message(message)
+                .build();
+            BackupError::from(service_error(ListObjectsV2Error::generic(err), 400))
+        };
+
+        assert!(
+            invalid_argument("The continuation token provided is incorrect")
+                .is_expired_continuation_token()
+        );
+        assert!(!invalid_argument("Invalid max-keys").is_expired_continuation_token());
+        assert!(!listing_error("NoSuchBucket").is_expired_continuation_token());
+    }
+
+    #[test]
+    fn retries_are_classified_by_their_status() {
+        let upload_error = |status| {
+            let err = aws_smithy_types::Error::builder().build();
+            BackupError::from(service_error(PutObjectError::generic(err), status))
+        };
+
+        assert_eq!(upload_error(503).retry_kind(), RetryKind::Throttling);
+        assert_eq!(upload_error(429).retry_kind(), RetryKind::Throttling);
+        assert_eq!(upload_error(500).retry_kind(), RetryKind::ServerError);
+        assert_eq!(upload_error(403).retry_kind(), RetryKind::Other);
+        assert_eq!(
+            BackupError::from(SdkError::<PutObjectError>::timeout_error("timed out")).retry_kind(),
+            RetryKind::Timeout
+        );
+    }
+
+    #[test]
+    fn unsent_request_is_a_connection_failure() {
+        let err = BackupError::from(SdkError::<PutObjectError>::dispatch_failure(
+            ConnectorError::io("connection reset".into()),
+        ));
+        assert!(err.is_connection_failure());
+
+        let err = BackupError::from(SdkError::<PutObjectError>::timeout_error("timed out"));
+        assert!(!err.is_connection_failure());
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        let upload_error = |code: &str, status| {
+            let err = aws_smithy_types::Error::builder().code(code).build();
+            BackupError::from(service_error(PutObjectError::generic(err), status))
+        };
+
+        assert!(upload_error("SlowDown", 503).is_retryable());
+        assert!(upload_error("InternalError", 500).is_retryable());
+        assert!(!upload_error("AccessDenied", 403).is_retryable());
+        assert!(!upload_error("NoSuchBucket", 404).is_retryable());
+        assert!(
+            BackupError::from(SdkError::<PutObjectError>::dispatch_failure(
+                ConnectorError::io("connection reset".into()),
+            ))
+            .is_retryable()
+        );
+    }
+
+    #[test]
+    fn retry_after_header_is_read_in_seconds() {
+        let throttled = |retry_after: &str| {
+            let response = http::Response::builder()
+                .status(503)
+                .header("Retry-After", retry_after)
+                .body(SdkBody::empty())
+                .unwrap();
+            let err = PutObjectError::generic(aws_smithy_types::Error::builder().build());
+            BackupError::from(SdkError::service_error(
+                err,
+                operation::Response::new(response),
+            ))
+        };
+
+        assert_eq!(throttled(" 7 ").retry_after(), Some(Duration::from_secs(7)));
+        assert_eq!(
+            throttled("Wed, 21 Oct 2015 07:28:00 GMT").retry_after(),
+            None
+        );
+        assert_eq!(
+            BackupError::from(service_error(
+                PutObjectError::generic(aws_smithy_types::Error::builder().build()),
+                503
+            ))
+            .retry_after(),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_index_counts_as_a_missing_object() {
+        let download_error = |kind| {
+            let err = GetObjectError::new(kind, aws_smithy_types::Error::builder().build());
+            BackupError::from(service_error(err, 404))
+        };
+
+        assert!(download_error(GetObjectErrorKind::NoSuchKey(
+            aws_sdk_s3::error::NoSuchKey::builder().build()
+        ))
+        .is_missing_object());
+        assert!(!download_error(GetObjectErrorKind::InvalidObjectState(
+            aws_sdk_s3::error::InvalidObjectState::builder().build()
+        ))
+        .is_missing_object());
+    }
+}
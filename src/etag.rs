@@ -0,0 +1,134 @@
+use md5::{Digest, Md5};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const MIB: u64 = 1 << 20;
+/// Part sizes used by common tools, such as the 8 MiB default of the AWS CLI
+const COMMON_PART_SIZES: [u64; 3] = [8 * MIB, 16 * MIB, 5 * MIB];
+
+/// The number of parts encoded in a multipart ETag such as `"9b2cf535f27731c974343645a3985328-3"`
+pub fn part_count(e_tag: &str) -> Option<u64> {
+    let (_, parts) = e_tag.trim_matches('"').rsplit_once('-')?;
+    parts.parse().ok()
+}
+
+/// Multipart uploads use equally sized parts apart from the last, which tools size in whole MiB
+pub fn infer_part_size(size: u64, parts: u64) -> u64 {
+    let parts = parts.max(1);
+    let part_size = size.div_ceil(parts);
+    part_size.div_ceil(MIB).max(1) * MIB
+}
+
+/// The part sizes which split `size` bytes into `parts` parts: the configured and common sizes
+/// first, since inferring the smallest fitting size is ambiguous, followed by the inferred size
+pub fn candidate_part_sizes(size: u64, parts: u64, configured: Option<u64>) -> Vec<u64> {
+    let mut candidates: Vec<u64> = configured
+        .into_iter()
+        .chain(COMMON_PART_SIZES)
+        .filter(|part_size| size.div_ceil(*part_size).max(1) == parts)
+        .collect();
+
+    let inferred = infer_part_size(size, parts);
+    if !candidates.contains(&inferred) {
+        candidates.push(inferred);
+    }
+    candidates
+}
+
+/// Computes the ETag S3 assigns to an unencrypted or SSE-S3 object: the MD5 of the content, or for
+/// multipart uploads the MD5 of the concatenated part MD5s followed by the number of parts
+pub fn compute(path: &Path, part_size: Option<u64>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+
+    let part_size = match part_size {
+        Some(part_size) => part_size,
+        None => {
+            let mut hasher = Md5::new();
+            io::copy(&mut file, &mut hasher)?;
+            return Ok(format!("{:x}", hasher.finalize()));
+        }
+    };
+
+    let mut part_digests = Vec::new();
+    let mut parts = 0;
+    loop {
+        let mut hasher = Md5::new();
+        let read = io::copy(&mut (&mut file).take(part_size), &mut hasher)?;
+        if read == 0 && parts > 0 {
+            break;
+        }
+
+        part_digests.extend_from_slice(&hasher.finalize());
+        parts += 1;
+        if read < part_size {
+            break;
+        }
+    }
+
+    Ok(format!("{:x}-{}", Md5::digest(&part_digests), parts))
+}
+
+/// Whether the file's content matches the remote ETag, trying every part size which fits the number
+/// of parts the ETag describes
+pub fn matches(
+    path: &Path,
+    size: u64,
+    e_tag: &str,
+    configured_part_size: Option<u64>,
+) -> io::Result<bool> {
+    let e_tag = e_tag.trim_matches('"');
+    let parts = match part_count(e_tag) {
+        Some(parts) => parts,
+        None => return Ok(compute(path, None)? == e_tag),
+    };
+
+    for part_size in candidate_part_sizes(size, parts, configured_part_size) {
+        if compute(path, Some(part_size))? == e_tag {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn common_part_sizes_are_tried_before_inferring() {
+        assert_eq!(infer_part_size(17 * MIB, 3), 6 * MIB);
+        assert_eq!(candidate_part_sizes(17 * MIB, 3, None), [8 * MIB, 6 * MIB]);
+        assert_eq!(
+            candidate_part_sizes(17 * MIB, 2, Some(10 * MIB)),
+            [10 * MIB, 16 * MIB, 9 * MIB]
+        );
+    }
+
+    #[test]
+    fn multipart_etag_matches_the_content_it_was_computed_from() {
+        let path = std::env::temp_dir().join(format!("backup-rs-etag-{}", std::process::id()));
+        let content: Vec<u8> = (0..17 * MIB).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let e_tag = compute(&path, Some(8 * MIB)).unwrap();
+        assert!(e_tag.ends_with("-3"));
+        assert!(matches(&path, 17 * MIB, &format!("\"{}\"", e_tag), None).unwrap());
+
+        let mut changed = content;
+        changed[0] ^= 1;
+        fs::write(&path, &changed).unwrap();
+        assert!(!matches(&path, 17 * MIB, &e_tag, None).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn single_part_etag_is_the_md5_of_the_content() {
+        assert_eq!(part_count("\"9b2cf535f27731c974343645a3985328\""), None);
+        assert_eq!(
+            part_count("\"9b2cf535f27731c974343645a3985328-3\""),
+            Some(3)
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use crate::errors::{BackupError, BackupResult};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Matches paths relative to the root against the inline and file-based exclude patterns,
+/// and files against the extension filters and the list of files to back up
+#[derive(Debug, Clone)]
+pub struct Excludes {
+    patterns: GlobSet,
+    only_extensions: Vec<String>,
+    skip_extensions: Vec<String>,
+    /// When set, only these files are backed up, e.g. the ones tracked by git
+    allowed_files: Option<HashSet<PathBuf>>,
+}
+
+impl Excludes {
+    pub fn new(inline: &[String], exclude_from: Option<&Path>) -> BackupResult<Excludes> {
+        let mut patterns = inline.to_vec();
+        if let Some(path) = exclude_from {
+            let contents = fs::read_to_string(path).map_err(BackupError::ExcludeFileUnreadable)?;
+            patterns.extend(parse_patterns(&contents));
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+
+        Ok(Excludes {
+            patterns: builder.build()?,
+            only_extensions: Vec::new(),
+            skip_extensions: Vec::new(),
+            allowed_files: None,
+        })
+    }
+
+    /// Extensions are compared case-insensitively and may be given with or without a leading dot
+    pub fn with_extensions(self, only: &[String], skip: &[String]) -> Excludes {
+        Excludes {
+            only_extensions: normalize_extensions(only),
+            skip_extensions: normalize_extensions(skip),
+            ..self
+        }
+    }
+
+    pub fn with_allowed_files(self, allowed_files: Option<HashSet<PathBuf>>) -> Excludes {
+        Excludes {
+            allowed_files,
+            ..self
+        }
+    }
+
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.patterns.is_match(relative_path)
+    }
+
+    /// Only applies to files, since directories are traversed regardless of their name's extension
+    /// and may contain allowed files
+    pub fn skips_file(&self, file: &Path) -> bool {
+        let allowed = self
+            .allowed_files
+            .as_ref()
+            .is_none_or(|allowed_files| allowed_files.contains(file));
+
+        !allowed || self.skips_extension(file)
+    }
+
+    fn skips_extension(&self, file: &Path) -> bool {
+        let extension = file
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension {
+            Some(extension) if self.skip_extensions.contains(&extension) => true,
+            Some(extension) if !self.only_extensions.is_empty() => {
+                !self.only_extensions.contains(&extension)
+            }
+            None => !self.only_extensions.is_empty(),
+            Some(_) => false,
+        }
+    }
+}
+
+fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect()
+}
+
+/// One pattern per line, ignoring blank lines and lines starting with `#`
+fn parse_patterns(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_from_a_file_are_merged_with_inline_ones() {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-exclude-from-{}", std::process::id()));
+        fs::write(&path, "# build output\ntarget/**\n\n  *.tmp  \n").unwrap();
+
+        let excludes = Excludes::new(&["*.log".to_owned()], Some(&path)).unwrap();
+        assert!(excludes.is_excluded(Path::new("target/debug/app")));
+        assert!(excludes.is_excluded(Path::new("notes.tmp")));
+        assert!(excludes.is_excluded(Path::new("app.log")));
+        assert!(!excludes.is_excluded(Path::new("src/main.rs")));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_exclude_file_is_an_error() {
+        let path = Path::new("/nonexistent/backup-rs-excludes");
+        assert!(matches!(
+            Excludes::new(&[], Some(path)),
+            Err(BackupError::ExcludeFileUnreadable(_))
+        ));
+    }
+
+    #[test]
+    fn extension_filters_are_case_insensitive() {
+        let excludes = Excludes::new(&[], None)
+            .unwrap()
+            .with_extensions(&["jpg".to_owned(), ".PNG".to_owned()], &[]);
+        assert!(!excludes.skips_file(Path::new("a.JPG")));
+        assert!(!excludes.skips_file(Path::new("a.png")));
+        assert!(excludes.skips_file(Path::new("a.txt")));
+        assert!(excludes.skips_file(Path::new("Makefile")));
+    }
+
+    #[test]
+    fn skipped_extensions_win_over_allowed_ones() {
+        let excludes = Excludes::new(&[], None)
+            .unwrap()
+            .with_extensions(&["log".to_owned()], &["LOG".to_owned(), "tmp".to_owned()]);
+        assert!(excludes.skips_file(Path::new("app.log")));
+        assert!(excludes.skips_file(Path::new("a.tmp")));
+
+        let excludes = Excludes::new(&[], None)
+            .unwrap()
+            .with_extensions(&[], &["tmp".to_owned()]);
+        assert!(!excludes.skips_file(Path::new("Makefile")));
+    }
+}
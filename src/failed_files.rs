@@ -0,0 +1,57 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Writes the failed paths terminated by NUL bytes, like `find -print0`, so names containing
+/// newlines survive being passed to `xargs -0`. A path of `-` writes to stderr instead.
+pub fn write_failed_files(path: &Path, failed: &[String], append: bool) -> io::Result<()> {
+    let contents = format_nul_separated(failed);
+
+    if path == Path::new("-") {
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(&contents)?;
+        return stderr.flush();
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?
+        .write_all(&contents)
+}
+
+fn format_nul_separated(failed: &[String]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    for file in failed {
+        contents.extend_from_slice(file.as_bytes());
+        contents.push(0);
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn failed_paths_are_nul_terminated() {
+        let failed = ["a.txt".to_owned(), "line\nbreak.txt".to_owned()];
+        assert_eq!(format_nul_separated(&failed), b"a.txt\0line\nbreak.txt\0");
+    }
+
+    #[test]
+    fn appending_keeps_the_earlier_failures() {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-failed-files-append-{}",
+            std::process::id()
+        ));
+
+        write_failed_files(&path, &["a".to_owned()], false).unwrap();
+        write_failed_files(&path, &["b".to_owned()], true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\0b\0");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use crate::errors::{BackupError, BackupResult};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Lists the tracked files under the root, recursing into submodules, and optionally the untracked
+/// ones which aren't ignored. git doesn't support both in one invocation, so they're listed separately.
+pub fn list_files(root: &Path, include_untracked: bool) -> BackupResult<HashSet<PathBuf>> {
+    let mut files = ls_files(root, &["--cached", "--recurse-submodules"])?;
+    if include_untracked {
+        files.extend(ls_files(root, &["--others", "--exclude-standard"])?);
+    }
+
+    Ok(files)
+}
+
+fn ls_files(root: &Path, flags: &[&str]) -> BackupResult<HashSet<PathBuf>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["ls-files", "-z"])
+        .args(flags)
+        .output()
+        .map_err(|err| BackupError::GitListingFailed(err.to_string()))?;
+
+    // Outside of a repository git explains as much on stderr, e.g. "not a git repository"
+    if !output.status.success() {
+        return Err(BackupError::GitListingFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    Ok(parse_nul_separated(&output.stdout)
+        .map(|file| root.join(file))
+        .collect())
+}
+
+/// Paths are relative to the directory git was run in, which is the root
+fn parse_nul_separated(output: &[u8]) -> impl Iterator<Item = PathBuf> + '_ {
+    output
+        .split(|byte| *byte == 0)
+        .filter(|file| !file.is_empty())
+        .map(|file| PathBuf::from(String::from_utf8_lossy(file).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_output_is_split_on_nul_bytes() {
+        let files: Vec<_> = parse_nul_separated(b"a.txt\0dir/new\nline.txt\0").collect();
+        assert_eq!(
+            files,
+            [PathBuf::from("a.txt"), PathBuf::from("dir/new\nline.txt")]
+        );
+    }
+
+    #[test]
+    fn listing_outside_of_a_repository_fails() {
+        let directory = std::env::temp_dir().join(format!(
+            "backup-rs-git-no-repository-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        assert!(matches!(
+            list_files(&directory, false),
+            Err(BackupError::GitListingFailed(_))
+        ));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn only_tracked_files_are_listed_unless_asked_for_untracked_ones() {
+        let root =
+            std::env::temp_dir().join(format!("backup-rs-git-repository-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        std::fs::write(root.join(".gitignore"), "ignored.log\n").unwrap();
+        std::fs::write(root.join("tracked.txt"), "tracked").unwrap();
+        std::fs::write(root.join("untracked.txt"), "untracked").unwrap();
+        std::fs::write(root.join("ignored.log"), "ignored").unwrap();
+        // Staged files are tracked, which avoids needing an identity to commit
+        git(&["add", ".gitignore", "tracked.txt"]);
+
+        assert_eq!(
+            list_files(&root, false).unwrap(),
+            HashSet::from([root.join(".gitignore"), root.join("tracked.txt")])
+        );
+        assert_eq!(
+            list_files(&root, true).unwrap(),
+            HashSet::from([
+                root.join(".gitignore"),
+                root.join("tracked.txt"),
+                root.join("untracked.txt")
+            ])
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
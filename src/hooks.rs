@@ -0,0 +1,36 @@
+use log::{error, info};
+use tokio::process::Command;
+
+/// Runs a command through the platform's shell, returning whether it exited successfully
+pub async fn run(description: &str, command: &str) -> bool {
+    info!("Running {}: {}", description, command);
+
+    let status = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).status().await
+    } else {
+        Command::new("sh").arg("-c").arg(command).status().await
+    };
+
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!("The {} failed with {}", description, status);
+            false
+        }
+        Err(err) => {
+            error!("Unable to run the {}: {}", description, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hook_succeeds_with_its_command() {
+        assert!(run("pre-backup hook", "exit 0").await);
+        assert!(!run("pre-backup hook", "exit 3").await);
+    }
+}
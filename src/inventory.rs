@@ -0,0 +1,118 @@
+use crate::s3::{encode_key, RemoteObject};
+
+use aws_sdk_s3::types::DateTime;
+use aws_smithy_types::date_time::Format as DateTimeFormat;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes the objects as rows in the CSV schema used by S3 Inventory reports:
+/// bucket, key, size, last modified date, storage class and ETag
+pub fn write_csv<'a>(
+    path: &Path,
+    bucket: &str,
+    objects: impl Iterator<Item = &'a RemoteObject>,
+    append: bool,
+) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let mut writer = BufWriter::new(file);
+
+    for object in objects {
+        let last_modified = match object.last_modified {
+            Some(secs) => DateTime::from_secs(secs)
+                .fmt(DateTimeFormat::DateTime)
+                .unwrap_or_default(),
+            None => String::new(),
+        };
+
+        let fields = [
+            bucket.to_owned(),
+            encode_key(&object.key),
+            object.size.to_string(),
+            last_modified,
+            object.storage_class.clone().unwrap_or_default(),
+            object
+                .e_tag
+                .as_deref()
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_owned(),
+        ];
+
+        let row: Vec<String> = fields
+            .iter()
+            .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+            .collect();
+        writeln!(writer, "{}", row.join(","))?;
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn object(key: &str, size: i64, e_tag: Option<&str>) -> RemoteObject {
+        RemoteObject {
+            key: key.to_owned(),
+            size,
+            last_modified: Some(1_700_000_000),
+            e_tag: e_tag.map(str::to_owned),
+            storage_class: Some("DEEP_ARCHIVE".to_owned()),
+        }
+    }
+
+    #[test]
+    fn rows_follow_the_inventory_schema() {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-inventory-{}.csv", std::process::id()));
+        let objects = [
+            object(
+                "photos/a b.jpg",
+                12,
+                Some("\"9b2cf535f27731c974343645a3985328\""),
+            ),
+            object("notes.txt", 0, None),
+        ];
+
+        write_csv(&path, "bucket", objects.iter(), false).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "\"bucket\",\"photos/a%20b.jpg\",\"12\",\"2023-11-14T22:13:20Z\",\"DEEP_ARCHIVE\",\"9b2cf535f27731c974343645a3985328\"",
+                "\"bucket\",\"notes.txt\",\"0\",\"2023-11-14T22:13:20Z\",\"DEEP_ARCHIVE\",\"\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn appending_keeps_earlier_rows() {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-inventory-append-{}.csv",
+            std::process::id()
+        ));
+        let first = [object("a", 1, None)];
+        let second = [object("b", 2, None)];
+
+        write_csv(&path, "bucket", first.iter(), false).unwrap();
+        write_csv(&path, "backup", second.iter(), true).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let buckets: Vec<&str> = csv
+            .lines()
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(buckets, ["\"bucket\"", "\"backup\""]);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::errors::{BackupError, BackupResult};
+
+use aws_sdk_s3::types::DateTime;
+use aws_smithy_types::date_time::Format as DateTimeFormat;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Prefix,
+    Host,
+    Date,
+    RelPath,
+}
+
+/// Renders keys such as `{prefix}/{host}/{date}/{relpath}`. The host and date are resolved once
+/// when the template is parsed, so every key of a run shares them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTemplate {
+    segments: Vec<Segment>,
+    host: String,
+    date: String,
+}
+
+impl KeyTemplate {
+    pub fn parse(template: &str) -> BackupResult<KeyTemplate> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+
+            let end = rest[start..].find('}').ok_or_else(|| {
+                BackupError::InvalidKeyTemplate("unclosed placeholder".to_owned())
+            })?;
+            let segment = match &rest[start + 1..start + end] {
+                "prefix" => Segment::Prefix,
+                "host" => Segment::Host,
+                "date" => Segment::Date,
+                "relpath" => Segment::RelPath,
+                unknown => {
+                    return Err(BackupError::InvalidKeyTemplate(format!(
+                        "unknown placeholder {{{}}}",
+                        unknown
+                    )))
+                }
+            };
+            segments.push(segment);
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+
+        if !segments.contains(&Segment::RelPath) {
+            return Err(BackupError::InvalidKeyTemplate(
+                "{relpath} is required to keep keys unique".to_owned(),
+            ));
+        }
+
+        Ok(KeyTemplate {
+            segments,
+            host: hostname(),
+            date: today(),
+        })
+    }
+
+    /// Empty placeholders, e.g. a missing prefix, don't leave empty directories behind in the key
+    pub fn render(&self, prefix: &str, relative_path: &str) -> String {
+        let rendered: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::Prefix => prefix,
+                Segment::Host => &self.host,
+                Segment::Date => &self.date,
+                Segment::RelPath => relative_path,
+            })
+            .collect();
+
+        rendered
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Shows the template with the host and date of this run filled in
+impl fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => f.write_str(literal)?,
+                Segment::Prefix => f.write_str("{prefix}")?,
+                Segment::Host => f.write_str(&self.host)?,
+                Segment::Date => f.write_str(&self.date)?,
+                Segment::RelPath => f.write_str("{relpath}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyTemplate {
+    type Err = BackupError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        KeyTemplate::parse(template)
+    }
+}
+
+fn hostname() -> String {
+    let host = system_hostname();
+    match host.trim() {
+        "" => "localhost".to_owned(),
+        host => host.to_owned(),
+    }
+}
+
+#[cfg(unix)]
+fn system_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer outlives the call, which writes at most its length
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+#[cfg(windows)]
+fn system_hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// The current UTC date as `YYYY-MM-DD`
+fn today() -> String {
+    DateTime::from(SystemTime::now())
+        .fmt(DateTimeFormat::DateTime)
+        .map(|date| date[..10].to_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_skips_empty_placeholders() {
+        let template = KeyTemplate::parse("{prefix}/{host}/{relpath}").unwrap();
+        let host = hostname();
+        assert_eq!(
+            template.render("backups", "dir/a.txt"),
+            format!("backups/{}/dir/a.txt", host)
+        );
+        assert_eq!(template.render("", "a.txt"), format!("{}/a.txt", host));
+    }
+
+    #[test]
+    fn parse_requires_the_relative_path() {
+        assert!(KeyTemplate::parse("{prefix}/{date}").is_err());
+        assert!(KeyTemplate::parse("{prefix}/{unknown}/{relpath}").is_err());
+        assert!(KeyTemplate::parse("{prefix/{relpath}").is_err());
+    }
+
+    #[test]
+    fn hostname_comes_from_the_system() {
+        assert!(!hostname().is_empty());
+        let template = KeyTemplate::parse("{host}/{date}/{relpath}").unwrap();
+        assert_eq!(
+            template.to_string(),
+            format!("{}/{}/{{relpath}}", hostname(), today())
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Backslashes are valid in unix file names, so only Windows treats them as separators
+#[cfg(windows)]
+pub const SEPARATORS: &[char] = &['/', '\\'];
+#[cfg(not(windows))]
+pub const SEPARATORS: &[char] = &['/'];
+
+/// Joins the segments of a relative path with `/`, the separator used in keys
+pub fn to_key(path: &str) -> String {
+    path.split(SEPARATORS).collect::<Vec<_>>().join("/")
+}
+
+/// Trailing whitespace and control characters make keys hard to manage and are rejected by some
+/// tools. With `safe_charset`, so is anything outside the characters which every S3-compatible
+/// provider accepts.
+pub fn has_problematic_characters(path: &str, safe_charset: bool) -> bool {
+    path.split(SEPARATORS).any(|segment| {
+        segment.ends_with(char::is_whitespace)
+            || segment
+                .chars()
+                .any(|c| c.is_control() || (safe_charset && !is_safe(c)))
+    })
+}
+
+/// The characters AWS lists as safe in object key names: ASCII letters and digits and `!-_.*'()`
+fn is_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '!' | '-' | '_' | '.' | '*' | '\'' | '(' | ')')
+}
+
+/// Trims trailing whitespace from every segment and replaces control characters, and with
+/// `safe_charset` any other characters which aren't safe, with underscores
+pub fn sanitize(path: &str, safe_charset: bool) -> String {
+    path.split(SEPARATORS)
+        .map(|segment| {
+            let sanitized: String = segment
+                .trim_end()
+                .chars()
+                .map(|c| {
+                    if c.is_control() || (safe_charset && !is_safe(c)) {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+
+            if sanitized.is_empty() && !segment.is_empty() {
+                "_".to_owned()
+            } else {
+                sanitized
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Drops the first `count` directories from the path, like tar's `--strip-components`. Paths
+/// without anything left once stripped return `None`.
+pub fn strip_components(path: &str, count: usize) -> Option<String> {
+    let segments: Vec<&str> = path.split(SEPARATORS).collect();
+    if segments.len() <= count {
+        return None;
+    }
+
+    Some(segments[count..].join("/"))
+}
+
+/// Appends `~n` to the file name, before its extension, to tell apart keys which would otherwise collide
+pub fn with_conflict_suffix(path: &str, n: usize) -> String {
+    let name_start = path.rfind(SEPARATORS).map_or(0, |index| index + 1);
+    match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = path.split_at(name_start + dot);
+            format!("{}~{}{}", stem, n, extension)
+        }
+        _ => format!("{}~{}", path, n),
+    }
+}
+
+/// Maps a key back to a path under the root by joining its segments with the platform's separator.
+/// Empty segments are dropped, and keys with any other segment which isn't a plain name, such as
+/// `..` or a drive like `C:`, return `None` so they can't point outside of the root.
+pub fn key_to_path(root: &Path, key: &str) -> Option<PathBuf> {
+    key.split(SEPARATORS)
+        .filter(|segment| !segment.is_empty())
+        .try_fold(root.to_path_buf(), |path, segment| {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => Some(path.join(segment)),
+                _ => None,
+            }
+        })
+}
+
+/// Removes a leading directory prefix such as `backups/laptop`, returning `None` for keys outside of it
+pub fn strip_key_prefix<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return Some(key);
+    }
+
+    key.strip_prefix(prefix)?.strip_prefix('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_trims_trailing_whitespace_and_replaces_control_characters() {
+        assert_eq!(sanitize("dir /a\u{7}b.txt ", false), "dir/a_b.txt");
+        assert_eq!(sanitize("  /a", false), "_/a");
+    }
+
+    #[test]
+    fn key_to_path_stays_under_the_root() {
+        let root = Path::new("restore");
+        assert_eq!(
+            key_to_path(root, "dir//a.txt"),
+            Some(root.join("dir").join("a.txt"))
+        );
+        assert_eq!(key_to_path(root, "dir/../../evil"), None);
+        assert_eq!(key_to_path(root, "./a.txt"), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn key_to_path_rejects_windows_traversal() {
+        let root = Path::new("restore");
+        assert_eq!(key_to_path(root, "a\\..\\..\\evil"), None);
+        assert_eq!(key_to_path(root, "C:\\evil"), None);
+        assert_eq!(key_to_path(root, "C:/evil"), None);
+    }
+
+    #[test]
+    fn safe_charset_only_keeps_the_characters_aws_lists_as_safe() {
+        assert!(!has_problematic_characters("My-Notes/(draft)_2*.txt", true));
+        assert!(has_problematic_characters("日記/notes.txt", true));
+        assert!(has_problematic_characters("my notes.txt", true));
+        assert!(has_problematic_characters("a&b.txt", true));
+        assert!(!has_problematic_characters("a&b.txt", false));
+        assert_eq!(sanitize("a&b/c#d.txt", true), "a_b/c_d.txt");
+        assert_eq!(sanitize("Ærø/my notes.txt", true), "_r_/my_notes.txt");
+    }
+
+    #[test]
+    fn conflict_suffix_goes_before_the_extension() {
+        assert_eq!(with_conflict_suffix("dir/a.txt", 1), "dir/a~1.txt");
+        assert_eq!(with_conflict_suffix("dir.d/a", 2), "dir.d/a~2");
+        assert_eq!(with_conflict_suffix(".profile", 1), ".profile~1");
+    }
+
+    #[test]
+    fn strip_components_drops_leading_directories() {
+        assert_eq!(
+            strip_components("home/user/a.txt", 1).as_deref(),
+            Some("user/a.txt")
+        );
+        assert_eq!(strip_components("home/a.txt", 2), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn strip_components_splits_on_backslashes() {
+        assert_eq!(
+            strip_components("home\\user\\a.txt", 2).as_deref(),
+            Some("a.txt")
+        );
+    }
+
+    #[test]
+    fn key_prefix_only_strips_whole_directories() {
+        assert_eq!(
+            strip_key_prefix("backups/laptop/a.txt", "backups/laptop/"),
+            Some("a.txt")
+        );
+        assert_eq!(
+            strip_key_prefix("backups/laptop2/a.txt", "backups/laptop"),
+            None
+        );
+        assert_eq!(strip_key_prefix("other/a.txt", "backups"), None);
+        assert_eq!(strip_key_prefix("a.txt", ""), Some("a.txt"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn backslashes_are_part_of_unix_file_names() {
+        assert_eq!(to_key("dir/a\\b.txt"), "dir/a\\b.txt");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn backslashes_separate_windows_paths() {
+        assert_eq!(to_key("dir\\a\\b.txt"), "dir/a/b.txt");
+    }
+}
@@ -0,0 +1,107 @@
+use crate::errors::BackupResult;
+use crate::s3::S3Client;
+
+use aws_sdk_s3::model::{
+    AbortIncompleteMultipartUpload, ExpirationStatus, LifecycleRule, LifecycleRuleFilter,
+};
+use log::{info, warn};
+
+const ABORT_RULE_ID: &str = "backup-rs-abort-incomplete-multipart-uploads";
+const ABORT_AFTER_DAYS: i32 = 7;
+const ABORT_RULE_JSON: &str = r#"{
+  "Rules": [
+    {
+      "ID": "backup-rs-abort-incomplete-multipart-uploads",
+      "Status": "Enabled",
+      "Filter": { "Prefix": "" },
+      "AbortIncompleteMultipartUpload": { "DaysAfterInitiation": 7 }
+    }
+  ]
+}"#;
+
+/// Interrupted multipart uploads keep their parts, which are billed but never listed as objects,
+/// until a lifecycle rule aborts them
+pub fn has_abort_rule(rules: &[LifecycleRule]) -> bool {
+    rules.iter().any(|rule| {
+        rule.status() == Some(&ExpirationStatus::Enabled)
+            && rule.abort_incomplete_multipart_upload().is_some()
+    })
+}
+
+pub fn abort_rule() -> LifecycleRule {
+    LifecycleRule::builder()
+        .id(ABORT_RULE_ID)
+        .status(ExpirationStatus::Enabled)
+        .filter(LifecycleRuleFilter::Prefix(String::new()))
+        .abort_incomplete_multipart_upload(
+            AbortIncompleteMultipartUpload::builder()
+                .days_after_initiation(ABORT_AFTER_DAYS)
+                .build(),
+        )
+        .build()
+}
+
+/// Suggests a rule which aborts incomplete multipart uploads when the bucket has none, or adds it
+/// to the existing rules when `apply` is set
+pub async fn check(client: &S3Client, apply: bool) -> BackupResult<()> {
+    let mut rules = client.lifecycle_rules().await?;
+    if has_abort_rule(&rules) {
+        return Ok(());
+    }
+
+    if apply {
+        rules.push(abort_rule());
+        client.put_lifecycle_rules(rules).await?;
+        info!(
+            "Added a lifecycle rule to {} which aborts incomplete multipart uploads after {} days",
+            client.bucket(),
+            ABORT_AFTER_DAYS
+        );
+    } else {
+        warn!(
+            "{} has no lifecycle rule to abort incomplete multipart uploads, whose parts are billed until they're aborted. \
+            Add it with --apply-lifecycle-suggestions or as the following rule:\n{}",
+            client.bucket(),
+            ABORT_RULE_JSON
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_an_enabled_abort_rule_counts() {
+        assert!(has_abort_rule(&[abort_rule()]));
+        assert!(!has_abort_rule(&[]));
+
+        let disabled = LifecycleRule::builder()
+            .status(ExpirationStatus::Disabled)
+            .abort_incomplete_multipart_upload(
+                AbortIncompleteMultipartUpload::builder()
+                    .days_after_initiation(1)
+                    .build(),
+            )
+            .build();
+        let expiration_only = LifecycleRule::builder()
+            .status(ExpirationStatus::Enabled)
+            .build();
+        assert!(!has_abort_rule(&[disabled, expiration_only]));
+    }
+
+    #[test]
+    fn suggested_rule_matches_the_added_one() {
+        let rule: serde_json::Value = serde_json::from_str(ABORT_RULE_JSON).unwrap();
+        let rule = &rule["Rules"][0];
+
+        assert_eq!(rule["ID"], ABORT_RULE_ID);
+        assert_eq!(
+            rule["AbortIncompleteMultipartUpload"]["DaysAfterInitiation"],
+            ABORT_AFTER_DAYS
+        );
+        assert_eq!(abort_rule().id(), Some(ABORT_RULE_ID));
+    }
+}
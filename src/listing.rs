@@ -0,0 +1,125 @@
+use crate::errors::BackupResult;
+use crate::s3::{RemoteObject, S3Client};
+
+use aws_sdk_s3::output::ListObjectsV2Output;
+use log::info;
+use tokio::task::JoinSet;
+
+const DELIMITER: &str = "/";
+
+/// Discovers the top-level prefixes of the bucket and lists each of them concurrently
+pub async fn fetch_in_parallel(
+    client: &S3Client,
+    max_parallelism: usize,
+) -> BackupResult<Vec<RemoteObject>> {
+    let mut objects = Vec::new();
+    let mut prefixes = Vec::new();
+    let mut next_token: Option<String> = None;
+
+    // Objects at the top level aren't part of any prefix so they're collected during discovery
+    loop {
+        let response = client
+            .list_objects(None, Some(DELIMITER), next_token)
+            .await?;
+        collect_page(&response, &mut objects, &mut prefixes);
+
+        next_token = response.next_continuation_token().map(|t| t.to_string());
+        if !response.is_truncated() {
+            break;
+        }
+    }
+
+    let parallelism = parallelism(prefixes.len(), max_parallelism);
+    info!(
+        "Listing {} prefixes with a parallelism of {}",
+        prefixes.len(),
+        parallelism
+    );
+
+    let mut pending = prefixes.into_iter();
+    let mut tasks = JoinSet::new();
+    loop {
+        while tasks.len() < parallelism {
+            match pending.next() {
+                Some(prefix) => {
+                    let client = client.clone();
+                    tasks.spawn(async move { fetch_prefix(&client, &prefix).await });
+                }
+                None => break,
+            }
+        }
+
+        match tasks.join_next().await {
+            Some(result) => objects.extend(result.expect("Listing task panicked")?),
+            None => return Ok(objects),
+        }
+    }
+}
+
+fn collect_page(
+    response: &ListObjectsV2Output,
+    objects: &mut Vec<RemoteObject>,
+    prefixes: &mut Vec<String>,
+) {
+    for object in response.contents().unwrap_or_default() {
+        objects.push(RemoteObject::from(object));
+    }
+    for common_prefix in response.common_prefixes().unwrap_or_default() {
+        if let Some(prefix) = common_prefix.prefix() {
+            prefixes.push(prefix.to_owned());
+        }
+    }
+}
+
+/// No more listings run at once than there are prefixes, but there's always at least one
+fn parallelism(prefixes: usize, max_parallelism: usize) -> usize {
+    prefixes.clamp(1, max_parallelism.max(1))
+}
+
+async fn fetch_prefix(client: &S3Client, prefix: &str) -> BackupResult<Vec<RemoteObject>> {
+    let mut objects = Vec::new();
+    let mut next_token: Option<String> = None;
+
+    loop {
+        let response = client.list_objects(Some(prefix), None, next_token).await?;
+        for object in response.contents().unwrap_or_default() {
+            objects.push(RemoteObject::from(object));
+        }
+
+        next_token = response.next_continuation_token().map(|t| t.to_string());
+        if !response.is_truncated() {
+            return Ok(objects);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::model::{CommonPrefix, Object};
+
+    #[test]
+    fn discovery_separates_top_level_objects_from_prefixes() {
+        let response = ListObjectsV2Output::builder()
+            .contents(Object::builder().key("top.txt").size(3).build())
+            .common_prefixes(CommonPrefix::builder().prefix("photos/").build())
+            .common_prefixes(CommonPrefix::builder().prefix("documents/").build())
+            .build();
+
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        collect_page(&response, &mut objects, &mut prefixes);
+
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "top.txt");
+        assert_eq!(prefixes, ["photos/", "documents/"]);
+    }
+
+    #[test]
+    fn parallelism_is_bounded_by_the_prefixes() {
+        assert_eq!(parallelism(3, 8), 3);
+        assert_eq!(parallelism(20, 8), 8);
+        assert_eq!(parallelism(0, 8), 1);
+        assert_eq!(parallelism(5, 0), 1);
+    }
+}
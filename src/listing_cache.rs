@@ -0,0 +1,320 @@
+use crate::s3::RemoteObject;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Listing progress persisted between runs so an interrupted listing can resume from its last page.
+/// The objects are appended to a JSON lines file one page at a time, next to a small state file
+/// with the continuation token, so saving a page doesn't rewrite the pages before it.
+#[derive(Debug)]
+pub struct ListingCache {
+    pub bucket: String,
+    pub continuation_token: Option<String>,
+    pub objects: Vec<RemoteObject>,
+    /// Set once the listing completed so it can be reused within the cache's TTL
+    pub completed_at: Option<i64>,
+    /// How many of the objects were written to the objects file already
+    saved: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ListingState {
+    bucket: String,
+    continuation_token: Option<String>,
+    /// The number of objects covered by the token, since an interruption can leave part of a page
+    /// behind in the objects file
+    objects: usize,
+    #[serde(default)]
+    completed_at: Option<i64>,
+}
+
+impl ListingCache {
+    pub fn new(bucket: &str) -> ListingCache {
+        ListingCache {
+            bucket: bucket.to_owned(),
+            continuation_token: None,
+            objects: Vec::new(),
+            completed_at: None,
+            saved: 0,
+        }
+    }
+
+    /// Loads the cached listing for the bucket, starting over when it's absent, unreadable or for another bucket
+    pub fn load(directory: &Path, bucket: &str) -> ListingCache {
+        let path = state_path(directory, bucket);
+        let cache = match read_cache(directory, bucket) {
+            Ok(cache) => cache,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return ListingCache::new(bucket),
+            Err(err) => {
+                warn!("Ignoring unreadable listing cache {:?}: {}", path, err);
+                return ListingCache::new(bucket);
+            }
+        };
+
+        if cache.bucket != bucket {
+            warn!(
+                "Ignoring listing cache {:?} since it belongs to bucket {}",
+                path, cache.bucket
+            );
+            return ListingCache::new(bucket);
+        }
+
+        cache
+    }
+
+    pub fn is_fresh(&self, ttl: u64, now: i64) -> bool {
+        matches!(self.completed_at, Some(completed_at) if now - completed_at <= ttl as i64)
+    }
+
+    /// Replaces the cached objects, which rewrites the objects file on the next save
+    pub fn replace_objects(&mut self, objects: Vec<RemoteObject>) {
+        self.objects = objects;
+        self.saved = 0;
+    }
+
+    /// Appends the objects added since the last save and then records the continuation token
+    pub fn save(&mut self, directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+
+        let objects_file = if self.saved == 0 {
+            File::create(objects_path(directory, &self.bucket))?
+        } else {
+            OpenOptions::new()
+                .append(true)
+                .open(objects_path(directory, &self.bucket))?
+        };
+        let mut writer = BufWriter::new(objects_file);
+        for object in &self.objects[self.saved..] {
+            writeln!(writer, "{}", serde_json::to_string(object)?)?;
+        }
+        writer.into_inner()?.sync_data()?;
+        self.saved = self.objects.len();
+
+        let state = ListingState {
+            bucket: self.bucket.clone(),
+            continuation_token: self.continuation_token.clone(),
+            objects: self.objects.len(),
+            completed_at: self.completed_at,
+        };
+
+        // Write to a temporary file first so an interruption never leaves a truncated state behind
+        let path = state_path(directory, &self.bucket);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(temp_path, path)
+    }
+
+    pub fn clear(directory: &Path, bucket: &str) -> io::Result<()> {
+        for path in [
+            state_path(directory, bucket),
+            objects_path(directory, bucket),
+        ] {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn state_path(directory: &Path, bucket: &str) -> PathBuf {
+    directory.join(format!("{}.listing.json", bucket))
+}
+
+fn objects_path(directory: &Path, bucket: &str) -> PathBuf {
+    directory.join(format!("{}.listing.jsonl", bucket))
+}
+
+fn read_cache(directory: &Path, bucket: &str) -> io::Result<ListingCache> {
+    let state: ListingState = serde_json::from_slice(&fs::read(state_path(directory, bucket))?)?;
+
+    let objects = if state.objects == 0 {
+        Vec::new()
+    } else {
+        // The state is only written after its objects, so they have to exist
+        let file = File::open(objects_path(directory, bucket)).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("objects missing: {}", err),
+            )
+        })?;
+        BufReader::new(file)
+            .lines()
+            .take(state.objects)
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<io::Result<Vec<RemoteObject>>>()?
+    };
+
+    if objects.len() < state.objects {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected {} objects but found {}",
+                state.objects,
+                objects.len()
+            ),
+        ));
+    }
+
+    Ok(ListingCache {
+        bucket: state.bucket,
+        continuation_token: state.continuation_token,
+        saved: objects.len(),
+        objects,
+        completed_at: state.completed_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "backup-rs-listing-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn object(key: &str) -> RemoteObject {
+        RemoteObject {
+            key: key.to_owned(),
+            size: 1,
+            last_modified: None,
+            e_tag: None,
+            storage_class: None,
+        }
+    }
+
+    fn keys(cache: &ListingCache) -> Vec<&str> {
+        cache
+            .objects
+            .iter()
+            .map(|object| object.key.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn resumed_listing_continues_from_the_stored_token() {
+        let directory = temp_directory("resume");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.extend([object("a"), object("b")]);
+        cache.continuation_token = Some("page-2".to_owned());
+        cache.save(&directory).unwrap();
+
+        let mut resumed = ListingCache::load(&directory, "bucket");
+        assert_eq!(resumed.continuation_token.as_deref(), Some("page-2"));
+        assert_eq!(keys(&resumed), ["a", "b"]);
+
+        resumed.objects.push(object("c"));
+        resumed.continuation_token = None;
+        resumed.save(&directory).unwrap();
+
+        let merged = ListingCache::load(&directory, "bucket");
+        assert_eq!(merged.continuation_token, None);
+        assert_eq!(keys(&merged), ["a", "b", "c"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn saving_a_page_only_appends_its_objects() {
+        let directory = temp_directory("append");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.push(object("a"));
+        cache.save(&directory).unwrap();
+        cache.objects.push(object("b"));
+        cache.save(&directory).unwrap();
+
+        let lines = fs::read_to_string(objects_path(&directory, "bucket")).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn partially_written_page_is_ignored() {
+        let directory = temp_directory("partial");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.push(object("a"));
+        cache.continuation_token = Some("page-2".to_owned());
+        cache.save(&directory).unwrap();
+
+        let mut objects = OpenOptions::new()
+            .append(true)
+            .open(objects_path(&directory, "bucket"))
+            .unwrap();
+        writeln!(objects, "{}", serde_json::to_string(&object("b")).unwrap()).unwrap();
+
+        let resumed = ListingCache::load(&directory, "bucket");
+        assert_eq!(keys(&resumed), ["a"]);
+        assert_eq!(resumed.continuation_token.as_deref(), Some("page-2"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn cache_of_another_bucket_starts_over() {
+        let directory = temp_directory("bucket");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.push(object("a"));
+        cache.continuation_token = Some("page-2".to_owned());
+        cache.save(&directory).unwrap();
+        fs::copy(
+            state_path(&directory, "bucket"),
+            state_path(&directory, "other"),
+        )
+        .unwrap();
+        fs::copy(
+            objects_path(&directory, "bucket"),
+            objects_path(&directory, "other"),
+        )
+        .unwrap();
+
+        let other = ListingCache::load(&directory, "other");
+        assert_eq!(other.continuation_token, None);
+        assert!(other.objects.is_empty());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn replaced_objects_rewrite_the_objects_file() {
+        let directory = temp_directory("replace");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.extend([object("a"), object("b")]);
+        cache.save(&directory).unwrap();
+
+        cache.replace_objects(vec![object("c")]);
+        cache.save(&directory).unwrap();
+
+        assert_eq!(keys(&ListingCache::load(&directory, "bucket")), ["c"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn completed_listing_is_reused_within_its_ttl() {
+        let directory = temp_directory("ttl");
+        let mut cache = ListingCache::new("bucket");
+        cache.objects.push(object("a"));
+        cache.completed_at = Some(1_000);
+        cache.save(&directory).unwrap();
+
+        let next_run = ListingCache::load(&directory, "bucket");
+        assert!(next_run.is_fresh(60, 1_060));
+        assert_eq!(keys(&next_run), ["a"]);
+        assert!(!next_run.is_fresh(60, 1_061));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn incomplete_listing_is_never_fresh() {
+        let mut cache = ListingCache::new("bucket");
+        cache.continuation_token = Some("page-2".to_owned());
+        assert!(!cache.is_fresh(60, 0));
+    }
+}
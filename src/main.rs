@@ -1,153 +1,1933 @@
+mod archive;
+mod case_folding;
+mod changes;
+mod checkpoint;
+mod checksums;
+mod clock;
+mod credentials;
+mod dedupe_index;
+mod dedupe_report;
+mod diff;
+mod disk_space;
 mod errors;
+mod etag;
+mod exclude;
+mod failed_files;
+mod git;
+mod hooks;
+mod inventory;
+mod key_template;
+mod keys;
+mod lifecycle;
+mod listing;
+mod listing_cache;
+mod metadata;
+mod migrate;
+mod multipart;
+mod notify;
 mod options;
+mod permissions;
+mod plan;
+mod power;
+mod progress_file;
+mod progress_signal;
+mod rate_limit;
+mod restore;
+mod retry;
 mod s3;
+mod skipped_report;
+mod summary;
+mod telemetry;
+mod throughput;
+mod verify;
 
+use crate::case_folding::FoldedKeys;
+use crate::changes::Change;
+use crate::checkpoint::Checkpoint;
+use crate::dedupe_report::DedupeReport;
+use crate::diff::ListingDiff;
 use crate::errors::{BackupError, BackupResult};
-use crate::options::Options as CLIopts;
-use crate::s3::S3Client;
+use crate::exclude::Excludes;
+use crate::listing_cache::ListingCache;
+use crate::metadata::{
+    birthtime, insecure_permissions, FileMetadata, BIRTHTIME_KEY, ORIGINAL_PATH_KEY,
+};
+use crate::notify::Notification;
+use crate::options::{Options as CLIopts, PermissionDeniedPolicy, ProblematicKeyPolicy};
+use crate::plan::{Plan, PlannedFile};
+use crate::power::PowerDecision;
+use crate::progress_file::ProgressFile;
+use crate::progress_signal::ProgressSignal;
+use crate::rate_limit::RequestLimiter;
+use crate::retry::RetryBudget;
+use crate::s3::{encode_key, RemoteObject, S3Client};
+use crate::skipped_report::{SkipReason, SkippedReport};
+use crate::summary::{format_bytes, log_telemetry, Summary};
+use crate::throughput::ThroughputTracker;
 
 use async_recursion::async_recursion;
 use aws_sdk_s3::types::ByteStream;
 use log::{debug, error, info, warn};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
+/// Keeps errors but only the informational logs of the final summary
+const SUMMARY_ONLY_FILTER: &str = "error,backup_rs::summary=info";
+/// Logs the final line of restores and migrations alongside the upload summary
+const SUMMARY_TARGET: &str = "backup_rs::summary";
+/// How often a listing is restarted after its continuation token expired before giving up
+const MAX_LISTING_RESTARTS: u32 = 3;
+
 #[tokio::main]
 async fn main() {
+    let mut args = CLIopts::from_args();
+    // Keeping the problematic keys would leave nothing for the safe charset to do
+    if args.safe_key_charset && args.on_problematic_key == ProblematicKeyPolicy::Keep {
+        args.on_problematic_key = ProblematicKeyPolicy::Sanitize;
+    }
+
+    // RUST_LOG still takes precedence, so the filter only sets the default
+    let default_filter = if args.summary_only {
+        SUMMARY_ONLY_FILTER
+    } else {
+        "info"
+    };
     env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, default_filter),
+    );
+    let request_limiter = args.max_requests_per_second.map(RequestLimiter::new);
+    let client = S3Client::new(
+        args.bucket.clone(),
+        args.region.clone(),
+        &args.storage_class,
+        &args.encryption,
+    )
+    .await
+    .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err))
+    .with_request_limit(request_limiter.clone())
+    .with_sha256_checksums(args.checksum_sha256);
+
+    if args.restore {
+        info!("Starting restore process");
+        if !restore_from_client(&client, &args).await {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(other_bucket) = args.diff_with.clone() {
+        let other_region = args
+            .diff_region
+            .clone()
+            .unwrap_or_else(|| args.region.clone());
+        let other = S3Client::new(
+            other_bucket,
+            other_region,
+            &args.storage_class,
+            &args.encryption,
+        )
+        .await
+        .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err))
+        .with_request_limit(request_limiter.clone());
+
+        if !diff_buckets(&client, &other, &args).await {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(destination_bucket) = args.migrate_to.clone() {
+        info!("Starting migration to {}", destination_bucket);
+        let destination_region = args
+            .migrate_region
+            .clone()
+            .unwrap_or_else(|| args.region.clone());
+        let server_side = destination_region == args.region;
+        let destination = S3Client::new(
+            destination_bucket,
+            destination_region,
+            &args.migrate_class,
+            &args.encryption,
+        )
+        .await
+        .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err))
+        .with_request_limit(request_limiter.clone());
+
+        if !migrate_from_client(&client, &destination, server_side, &args).await {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(minutes) = args.verify_credentials_expiry {
+        if let Err(err) =
+            credentials::check_expiry(args.region.clone(), Duration::from_secs(minutes * 60)).await
+        {
+            error!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    // Copying an object requires reading it, which archived objects don't allow until restored
+    if archive::is_archived_class(&args.storage_class)
+        && (args.dedupe_within_run || args.dedupe_index.is_some())
+    {
+        error!(
+            "Deduplication copies objects, which isn't possible in the {} storage class",
+            args.storage_class
+        );
+        process::exit(1);
+    }
+
+    // Verifying an upload downloads it, which archived objects don't allow until restored
+    if archive::is_archived_class(&args.storage_class) && args.verify_after_upload {
+        error!(
+            "Verifying uploads downloads them, which isn't possible in the {} storage class",
+            args.storage_class
+        );
+        process::exit(1);
+    }
+
+    let pause_between_files = match power::decide(
+        power::current_state(),
+        args.skip_on_battery,
+        args.throttle_on_battery,
+    ) {
+        PowerDecision::Skip => {
+            info!("Skipping backup while running on battery");
+            return;
+        }
+        PowerDecision::Throttle => {
+            info!("Throttling backup while running on battery");
+            args.parallel_list_prefixes = args.parallel_list_prefixes.map(|_| 1);
+            Some(Duration::from_millis(args.battery_pause))
+        }
+        PowerDecision::Proceed => None,
+    };
+
+    if !clock::check(&client, args.max_clock_skew, args.on_clock_skew).await {
+        process::exit(1);
+    }
+
+    let backup_client = S3Client::new(
+        args.bucket_backup
+            .clone()
+            .expect("No backup bucket provided"),
+        args.region_backup.clone(),
+        &args.storage_class,
+        &args.encryption,
+    )
+    .await
+    .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err))
+    .with_request_limit(request_limiter.clone())
+    .with_sha256_checksums(args.checksum_sha256);
+
+    if args.configure_tiering {
+        for s3_client in [&client, &backup_client] {
+            match s3_client
+                .configure_intelligent_tiering(
+                    args.archive_access_days,
+                    args.deep_archive_access_days,
+                )
+                .await
+            {
+                Ok(_) => info!("Configured Intelligent-Tiering for {}", s3_client.bucket()),
+                Err(err) => {
+                    error!("Failed to configure {}: {}", s3_client.bucket(), err);
+                    process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    if args.check_lifecycle || args.apply_lifecycle_suggestions {
+        for s3_client in [&client, &backup_client] {
+            if let Err(err) = lifecycle::check(s3_client, args.apply_lifecycle_suggestions).await {
+                warn!(
+                    "Unable to check the lifecycle rules of {}: {}",
+                    s3_client.bucket(),
+                    err
+                );
+            }
+        }
+    }
+
+    let excludes = Excludes::new(&args.exclude, args.exclude_from.as_deref())
+        .unwrap_or_else(|err| {
+            error!("Unable to load the exclude patterns: {}", err);
+            process::exit(1);
+        })
+        .with_extensions(&args.only_extensions, &args.skip_extensions);
+
+    let succeeded = run_between_hooks(
+        &args,
+        back_up(
+            &client,
+            &backup_client,
+            &args,
+            excludes,
+            pause_between_files,
+        ),
+    )
+    .await;
+
+    if !succeeded {
+        process::exit(1);
+    }
+}
+
+/// Runs the pre-command, then the backup itself and finally the post-command, which runs on every
+/// exit path since the pre-command may have partially set things up, e.g. created a snapshot
+async fn run_between_hooks(args: &CLIopts, run: impl std::future::Future<Output = bool>) -> bool {
+    if let Some(pre_command) = &args.pre_command {
+        if !hooks::run("pre-command", pre_command).await {
+            run_post_command(args).await;
+            return false;
+        }
+    }
+
+    let succeeded = run.await;
+    let cleaned_up = run_post_command(args).await;
+    succeeded && cleaned_up
+}
+
+async fn back_up(
+    client: &S3Client,
+    backup_client: &S3Client,
+    args: &CLIopts,
+    excludes: Excludes,
+    pause_between_files: Option<Duration>,
+) -> bool {
+    // Resolved after the pre-command since that may prepare the root, e.g. by mounting a snapshot
+    let root = match expand_path(args.path.clone()) {
+        Ok(root) => root,
+        Err(err) => {
+            error!("Failed to read root path: {}", err);
+            return false;
+        }
+    };
+
+    // An unmounted mount point looks like an empty root, which would otherwise back up nothing
+    if let Some(marker) = missing_marker(args, &root) {
+        error!("The marker {:?} is missing from {:?}", marker, root);
+        return false;
+    }
+
+    let git_files = if args.from_git {
+        match git::list_files(&root, args.git_untracked) {
+            Ok(files) => Some(files),
+            Err(err) => {
+                error!("{}", err);
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+    let excludes = excludes.with_allowed_files(git_files);
+
+    if args.dedupe_report {
+        DedupeReport::from_hashes(dedupe_report::hash_tree(&root, &excludes)).log();
+        return true;
+    }
+
+    info!("Starting upload process");
+    let succeeded = upload_to_client(client, args, &excludes, false, pause_between_files).await;
+
+    info!("Starting upload process for backups");
+    let backup_succeeded =
+        upload_to_client(backup_client, args, &excludes, true, pause_between_files).await;
+
+    succeeded && backup_succeeded
+}
+
+/// Everything the traversal of a single bucket keeps track of as it goes
+struct RunState {
+    existing_files: HashMap<Vec<String>, RemoteObject>,
+    summary: Summary,
+    checkpoint: Option<Checkpoint>,
+    /// The first key uploaded for every content hash seen during this run
+    uploaded_by_hash: HashMap<String, String>,
+    /// The content hash of every key written during this run, which index entries must agree with
+    written_hashes: HashMap<String, String>,
+    retry_budget: RetryBudget,
+    /// The relative path and SHA-256 of every file uploaded during this run
+    checksums: Vec<(String, String)>,
+    excludes: Excludes,
+    claims: WalkClaims,
+    progress_signal: ProgressSignal,
+    /// Uploads which failed in a row because the request couldn't be sent
+    connection_failures: u32,
+    skipped_report: Option<SkippedReport>,
+    progress_file: Option<ProgressFile>,
+    /// How long to pause before hashing and uploading each file, to go easy on a battery
+    pause_between_files: Option<Duration>,
+    throughput: Option<ThroughputTracker>,
+}
+
+/// The keys and directories a walk has used so far. Planning walks with claims of its own so it
+/// resolves every key like the upload which follows it.
+#[derive(Default)]
+struct WalkClaims {
+    /// The keys used so far, tracked when the bucket treats keys case-insensitively
+    folded_keys: Option<FoldedKeys>,
+    /// The keys used so far, tracked when problematic names are sanitized so a sanitized name can't
+    /// take the key of another file
+    claimed_keys: Option<HashSet<String>>,
+    /// The canonical paths of the directories walked so far, to walk symlinked directories once
+    walked_directories: HashSet<PathBuf>,
+}
+
+impl WalkClaims {
+    fn new(args: &CLIopts, folded_keys: Option<FoldedKeys>) -> WalkClaims {
+        WalkClaims {
+            folded_keys,
+            claimed_keys: (args.on_problematic_key == ProblematicKeyPolicy::Sanitize)
+                .then(HashSet::new),
+            walked_directories: HashSet::new(),
+        }
+    }
+}
+
+/// Prints the keys which differ between the buckets, succeeding only when they hold the same objects
+async fn diff_buckets(client: &S3Client, other: &S3Client, args: &CLIopts) -> bool {
+    let objects = match fetch_inventory(client, args).await {
+        Ok(objects) => objects,
+        Err(err) => {
+            error!("Failed to list the objects in {}: {}", client.bucket(), err);
+            return false;
+        }
+    };
+    let other_objects = match fetch_existing_objects(other, None, None).await {
+        Ok(objects) => objects,
+        Err(err) => {
+            error!("Failed to list the objects in {}: {}", other.bucket(), err);
+            return false;
+        }
+    };
+    let other_objects = other_objects
+        .into_iter()
+        .filter(|object| !is_dedupe_index(object, args));
+
+    let diff = ListingDiff::compare(objects.into_values(), other_objects);
+    if diff.is_empty() {
+        info!(
+            "{} and {} hold the same objects",
+            client.bucket(),
+            other.bucket()
+        );
+        return true;
+    }
+
+    println!("{}", diff.describe(client.bucket(), other.bucket()));
+    info!(
+        "{} keys only in {}, {} only in {}, {} differing",
+        diff.only_in_a.len(),
+        client.bucket(),
+        diff.only_in_b.len(),
+        other.bucket(),
+        diff.differing.len()
+    );
+    false
+}
+
+async fn migrate_from_client(
+    source: &S3Client,
+    destination: &S3Client,
+    server_side: bool,
+    args: &CLIopts,
+) -> bool {
+    let objects = match fetch_inventory(source, args).await {
+        Ok(objects) => objects,
+        Err(err) => {
+            error!("Failed to list the objects in {}: {}", source.bucket(), err);
+            return false;
+        }
+    };
+
+    info!("Found {} objects", objects.len());
+
+    let objects = objects.into_values().collect();
+    let summary = migrate::migrate_objects(source, destination, objects, server_side, args).await;
+
+    info!(
+        target: SUMMARY_TARGET,
+        "Copied {} objects and transferred {} to {}, {} awaiting retrieval, {} failed to migrate",
+        summary.copied,
+        summary.transferred,
+        destination.bucket(),
+        summary.pending.len(),
+        summary.failed.len()
     );
 
-    let args = CLIopts::from_args();
-    let client = S3Client::new(
-        args.bucket,
-        args.region,
-        &args.storage_class,
-        &args.encryption,
-    )
-    .await
-    .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err));
+    summary.succeeded()
+}
+
+async fn run_post_command(args: &CLIopts) -> bool {
+    match &args.post_command {
+        Some(post_command) => hooks::run("post-command", post_command).await,
+        None => true,
+    }
+}
+
+async fn upload_to_client(
+    client: &S3Client,
+    args: &CLIopts,
+    excludes: &Excludes,
+    append_reports: bool,
+    pause_between_files: Option<Duration>,
+) -> bool {
+    let root = expand_path(args.path.clone())
+        .unwrap_or_else(|err| panic!("Failed to read root path: {}", err));
+
+    if args.report_dangling_multipart {
+        match multipart::find_dangling(client).await {
+            Ok(uploads) => multipart::report(client.bucket(), &uploads, unix_timestamp()),
+            Err(err) => warn!("Unable to list incomplete multipart uploads: {}", err),
+        }
+    }
+
+    let resumed = match (&args.checkpoint, args.resume) {
+        (Some(directory), true) => match Checkpoint::resume(
+            directory,
+            client.bucket(),
+            &root,
+            args.prefix.as_deref(),
+            args.key_template.as_ref(),
+        ) {
+            Ok((checkpoint, objects)) => {
+                info!("Resuming with {} files from the checkpoint", objects.len());
+                Some((checkpoint, objects))
+            }
+            Err(err) => {
+                warn!("Unable to resume from the checkpoint: {}", err);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let (checkpoint, files_by_path) = match resumed {
+        Some((checkpoint, objects)) => (
+            Some(checkpoint),
+            objects
+                .into_iter()
+                .map(|object| (split_filename(&object.key), object))
+                .collect(),
+        ),
+        None => {
+            let checkpoint = args.checkpoint.as_ref().and_then(|directory| {
+                Checkpoint::create(
+                    directory,
+                    client.bucket(),
+                    &root,
+                    args.prefix.as_deref(),
+                    args.key_template.as_ref(),
+                )
+                .map_err(|err| warn!("Unable to create a checkpoint: {}", err))
+                .ok()
+            });
+            match fetch_inventory(client, args).await {
+                Ok(objects) => (checkpoint, objects),
+                Err(err) => {
+                    error!("Failed to list the objects in {}: {}", client.bucket(), err);
+                    return false;
+                }
+            }
+        }
+    };
+
+    info!("Found {} objects", files_by_path.len());
+
+    // Detecting it uploads probes, which a dry run mustn't do
+    let case_insensitive = if args.detect_case_folding && !args.dry_run {
+        match case_folding::is_case_insensitive(client).await {
+            Ok(true) => {
+                warn!(
+                    "{} treats keys case-insensitively, files whose keys only differ in case get a ~n suffix",
+                    client.bucket()
+                );
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!("Unable to detect whether keys are case-sensitive: {}", err);
+                false
+            }
+        }
+    } else {
+        false
+    };
+    let fold_remote_keys = |case_insensitive: bool| {
+        case_insensitive
+            .then(|| FoldedKeys::new(files_by_path.values().map(|remote| remote.key.as_str())))
+    };
+
+    let mut progress_file = None;
+    if args.interactive
+        || args.dry_run
+        || args.max_new_files.is_some()
+        || args.progress_file.is_some()
+    {
+        let mut plan = Plan::default();
+        let mut claims = WalkClaims::new(args, fold_remote_keys(case_insensitive));
+        plan_directories(
+            &root,
+            &root,
+            &files_by_path,
+            args,
+            excludes,
+            &mut claims,
+            &mut plan,
+        );
+        progress_file = args
+            .progress_file
+            .clone()
+            .map(|path| ProgressFile::new(path, plan.new_files + plan.changed_files, plan.bytes));
+        if args.interactive || args.dry_run {
+            println!("{}", plan.describe(client.bucket()));
+        }
+
+        if let Some(budget) = args.budget {
+            println!("{}", plan.describe_budget(budget, args.budget_priority));
+        }
+
+        if args.dry_run {
+            if !args.check_permissions {
+                return true;
+            }
+
+            let denied = permissions::probe_prefixes(client, &plan.prefixes).await;
+            for (prefix, reason) in &denied {
+                println!(
+                    "No permission to upload to {} in {}: {}",
+                    prefix,
+                    client.bucket(),
+                    reason
+                );
+            }
+            return denied.is_empty();
+        }
+
+        // Too many new files usually means a wrong root or missing excludes, which is worth
+        // confirming even with --yes
+        let exceeds_limit = exceeds_new_file_limit(args, &plan);
+        if exceeds_limit {
+            error!(
+                "Uploading to {} would add {} new files, more than the limit of {}",
+                client.bucket(),
+                plan.new_files,
+                args.max_new_files.unwrap_or_default()
+            );
+            if !args.interactive {
+                return false;
+            }
+        }
+
+        if args.interactive && (!args.yes || exceeds_limit) {
+            match plan::confirm(&mut io::stdin().lock()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("Not uploading to {}", client.bucket());
+                    return true;
+                }
+                Err(err) => {
+                    error!("Unable to read the confirmation: {}", err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    let skipped_report = args.skipped_report.as_ref().and_then(|path| {
+        SkippedReport::create(path, append_reports)
+            .map_err(|err| warn!("Unable to create the skipped report {:?}: {}", path, err))
+            .ok()
+    });
+
+    let progress_signal = if args.checkpoint_on_signal {
+        ProgressSignal::listen().unwrap_or_else(|err| {
+            warn!("Unable to listen for progress requests: {}", err);
+            ProgressSignal::default()
+        })
+    } else {
+        ProgressSignal::default()
+    };
+
+    let second = root.clone();
+    let mut summary = Summary {
+        per_directory: args.report_per_directory.then(Default::default),
+        age_distribution: args.report_age_distribution.then(Default::default),
+        storage_classes: args
+            .report_storage_class_distribution
+            .then(Default::default),
+        ..Default::default()
+    };
+    for remote in files_by_path.values() {
+        summary.record_storage_class(remote.storage_class.as_deref(), remote.size as u64);
+    }
+
+    let uploaded_by_hash = match &args.dedupe_index {
+        Some(index_key) => match dedupe_index::load(client, index_key).await {
+            Ok(index) => {
+                info!("Loaded {} hashes from the deduplication index", index.len());
+                index
+            }
+            Err(err) => {
+                warn!(
+                    "Unable to load the deduplication index {}: {}",
+                    index_key, err
+                );
+                HashMap::new()
+            }
+        },
+        None => HashMap::new(),
+    };
+
+    let folded_keys = fold_remote_keys(case_insensitive);
+    let mut state = RunState {
+        existing_files: files_by_path,
+        summary,
+        checkpoint,
+        uploaded_by_hash,
+        written_hashes: HashMap::new(),
+        retry_budget: RetryBudget::new(args.retries_per_file, args.retry_budget),
+        checksums: Vec::new(),
+        excludes: excludes.clone(),
+        claims: WalkClaims::new(args, folded_keys),
+        progress_signal,
+        connection_failures: 0,
+        skipped_report,
+        progress_file,
+        pause_between_files,
+        throughput: args.report_throughput.then(ThroughputTracker::start),
+    };
+    let result = traverse_directories(&root, &second, client, args, &mut state).await;
+    let RunState {
+        existing_files: files_by_path,
+        mut summary,
+        checkpoint,
+        checksums,
+        skipped_report,
+        progress_file,
+        uploaded_by_hash,
+        written_hashes,
+        throughput,
+        ..
+    } = state;
+
+    if let Some(index_key) = &args.dedupe_index {
+        if let Err(err) =
+            dedupe_index::save(client, index_key, uploaded_by_hash, &written_hashes).await
+        {
+            warn!(
+                "Unable to update the deduplication index {}: {}",
+                index_key, err
+            );
+        }
+    }
+
+    if let Some(mut progress_file) = progress_file {
+        if let Err(err) = progress_file.write(&summary) {
+            warn!("Unable to write the progress file: {}", err);
+        }
+    }
+
+    if let Some(skipped_report) = skipped_report {
+        if let Err(err) = skipped_report.finish() {
+            warn!("Unable to write the skipped report: {}", err);
+        }
+    }
+
+    if let Some(throughput) = throughput {
+        summary.throughput = Some(throughput.finish(summary.bytes_uploaded));
+    }
+
+    if let Some(failed_path) = &args.failed_files_out {
+        if let Err(err) =
+            failed_files::write_failed_files(failed_path, &summary.failed, append_reports)
+        {
+            error!(
+                "Failed to write the failed files to {:?}: {}",
+                failed_path, err
+            );
+        }
+    }
+
+    let succeeded = match result {
+        Ok(()) => {
+            info!("All directories synced");
+            summary.log(client.bucket());
+            log_telemetry(client.bucket(), &client.telemetry().snapshot());
+
+            let empty = is_unexpectedly_empty(args, &summary);
+            if empty {
+                error!("No files found to back up in {:?}", root);
+            }
+
+            write_reports(
+                client,
+                args,
+                append_reports,
+                &files_by_path,
+                &checksums,
+                summary.failed.is_empty(),
+            ) && !empty
+                && summary.succeeded()
+        }
+        Err(err) => {
+            error!("Failed to sync directories: {}", err);
+            summary.log(client.bucket());
+            log_telemetry(client.bucket(), &client.telemetry().snapshot());
+            false
+        }
+    };
+
+    if let Some(webhook) = &args.notify_webhook {
+        if args.notify_on.applies_to(succeeded) {
+            let notification = Notification::new(client.bucket(), succeeded, &summary);
+            if let Err(err) = notify::send(webhook, &notification).await {
+                warn!("Failed to notify {}: {}", webhook, err);
+            }
+        }
+    }
+
+    if succeeded {
+        if let Some(checkpoint) = checkpoint {
+            if let Err(err) = checkpoint.complete() {
+                warn!("Unable to remove the checkpoint: {}", err);
+            }
+        }
+    }
+
+    succeeded
+}
+
+/// With --fail-on-empty a run which found nothing to back up, e.g. because a mount is missing, fails
+fn is_unexpectedly_empty(args: &CLIopts, summary: &Summary) -> bool {
+    args.fail_on_empty && summary.candidates == 0
+}
+
+/// The required marker, when the root doesn't contain it
+fn missing_marker<'a>(args: &'a CLIopts, root: &Path) -> Option<&'a Path> {
+    args.require_marker
+        .as_deref()
+        .filter(|marker| !root.join(marker).exists())
+}
+
+fn exceeds_new_file_limit(args: &CLIopts, plan: &Plan) -> bool {
+    args.max_new_files
+        .is_some_and(|max_new_files| plan.new_files > max_new_files)
+}
+
+/// Zero-byte files are uploaded as empty objects unless asked to skip them
+fn skips_empty_file(args: &CLIopts, size: u64) -> bool {
+    args.skip_empty_files && size == 0
+}
+
+/// An empty object for a non-empty file is left behind by an interrupted upload
+fn is_incomplete_upload(remote: &RemoteObject, local_size: u64) -> bool {
+    remote.size == 0 && local_size > 0
+}
+
+/// Writes the inventory and checksums and refreshes the listing cache, returning whether the
+/// reports could be written. The listing cache is only refreshed by runs without failures.
+fn write_reports(
+    client: &S3Client,
+    args: &CLIopts,
+    append_reports: bool,
+    files_by_path: &HashMap<Vec<String>, RemoteObject>,
+    checksums: &[(String, String)],
+    refresh_cache: bool,
+) -> bool {
+    if let Some(inventory_path) = &args.inventory_csv {
+        if let Err(err) = inventory::write_csv(
+            inventory_path,
+            client.bucket(),
+            files_by_path.values(),
+            append_reports,
+        ) {
+            error!(
+                "Failed to write the inventory to {:?}: {}",
+                inventory_path, err
+            );
+            return false;
+        }
+    }
+
+    if let Some(checksums_path) = &args.checksums_file {
+        if let Err(err) = checksums::write_checksums(checksums_path, checksums, append_reports) {
+            error!(
+                "Failed to write the checksums to {:?}: {}",
+                checksums_path, err
+            );
+            return false;
+        }
+    }
+
+    if let (Some(directory), Some(_)) = (&args.resume_listing, args.listing_cache_ttl) {
+        if refresh_cache {
+            refresh_listing_cache(directory, client.bucket(), files_by_path);
+        } else if let Err(err) = ListingCache::clear(directory, client.bucket()) {
+            warn!("Unable to remove the listing cache: {}", err);
+        }
+    }
+
+    true
+}
+
+async fn restore_from_client(client: &S3Client, args: &CLIopts) -> bool {
+    let objects = match fetch_inventory(client, args).await {
+        Ok(objects) => objects,
+        Err(err) => {
+            error!("Failed to list the objects in {}: {}", client.bucket(), err);
+            return false;
+        }
+    };
+
+    info!("Found {} objects", objects.len());
+
+    let root = expand_path(args.path.clone())
+        .unwrap_or_else(|err| panic!("Failed to read root path: {}", err));
+
+    let mut objects = objects.into_values().collect::<Vec<_>>();
+    if let Some(prefix) = &args.strip_prefix {
+        let (inside, outside): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|object| keys::strip_key_prefix(&object.key, prefix).is_some());
+        if !outside.is_empty() {
+            warn!(
+                "Skipping {} objects outside of the prefix {:?}",
+                outside.len(),
+                prefix
+            );
+        }
+        objects = inside;
+    }
+    if !args.filter_metadata.is_empty() {
+        objects = match restore::select_by_metadata(client, objects, &args.filter_metadata).await {
+            Ok(selected) => selected,
+            Err(err) => {
+                error!("Failed to filter objects by their metadata: {}", err);
+                return false;
+            }
+        };
+        info!(
+            "Selected {} objects matching the metadata filters",
+            objects.len()
+        );
+    }
+
+    let summary = restore::restore_objects(client, objects, &root, args).await;
+
+    info!(
+        target: SUMMARY_TARGET,
+        "Restored {} objects, kept {} existing files, {} failed verification, {} quarantined, {} awaiting retrieval, {} failed to restore",
+        summary.restored,
+        summary.kept,
+        summary.mismatched.len(),
+        summary.quarantined.len(),
+        summary.pending.len(),
+        summary.failed.len()
+    );
+
+    summary.succeeded()
+}
+
+async fn fetch_inventory(
+    client: &S3Client,
+    args: &CLIopts,
+) -> BackupResult<HashMap<Vec<String>, RemoteObject>> {
+    let result = match args.parallel_list_prefixes {
+        Some(max_parallelism) => listing::fetch_in_parallel(client, max_parallelism).await,
+        None => {
+            fetch_existing_objects(
+                client,
+                args.resume_listing.as_deref(),
+                args.listing_cache_ttl,
+            )
+            .await
+        }
+    };
+
+    let objects = match result {
+        Err(err) if args.treat_list_error_as_empty && err.is_empty_listing() => {
+            warn!(
+                "Treating the listing of {} as empty: {}",
+                client.bucket(),
+                err
+            );
+            Vec::new()
+        }
+        result => result?,
+    };
+
+    Ok(objects
+        .into_iter()
+        .filter(|object| !is_dedupe_index(object, args))
+        .map(|object| (split_filename(&object.key), object))
+        .collect())
+}
+
+/// The deduplication index is stored next to the backed up files but isn't one of them
+fn is_dedupe_index(object: &RemoteObject, args: &CLIopts) -> bool {
+    args.dedupe_index.as_deref() == Some(object.key.as_str())
+}
+
+async fn fetch_existing_objects(
+    client: &S3Client,
+    listing_cache: Option<&Path>,
+    ttl: Option<u64>,
+) -> BackupResult<Vec<RemoteObject>> {
+    let mut cache = match listing_cache {
+        Some(directory) => ListingCache::load(directory, client.bucket()),
+        None => ListingCache::new(client.bucket()),
+    };
+
+    if cache.completed_at.is_some() {
+        if ttl.is_some_and(|ttl| cache.is_fresh(ttl, unix_timestamp())) {
+            info!(
+                "Reusing the cached listing of {} objects",
+                cache.objects.len()
+            );
+            return Ok(cache.objects);
+        }
+
+        cache = ListingCache::new(client.bucket());
+    }
+
+    if cache.continuation_token.is_some() {
+        info!(
+            "Resuming listing from the cache with {} known objects",
+            cache.objects.len()
+        );
+    }
+
+    let mut restarts = 0;
+    let mut start_after = None;
+    let mut known_keys = HashSet::new();
+
+    loop {
+        let response = match client
+            .fetch_existing_objects(cache.continuation_token.clone(), start_after.clone())
+            .await
+        {
+            Ok(response) => response,
+            // Listings are ordered by key, so the last key collected is a safe point to restart from
+            Err(err) if err.is_expired_continuation_token() && restarts < MAX_LISTING_RESTARTS => {
+                restarts += 1;
+                start_after = cache.objects.last().map(|object| object.key.clone());
+                warn!(
+                    "The listing's continuation token expired, restarting after {:?}",
+                    start_after
+                );
+                cache.continuation_token = None;
+                known_keys = cache
+                    .objects
+                    .iter()
+                    .map(|object| object.key.clone())
+                    .collect();
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        for object in response.contents().unwrap_or_default() {
+            let object = RemoteObject::from(object);
+            // Not every provider honours the start key, so a restarted listing may repeat objects
+            if !known_keys.contains(&object.key) {
+                cache.objects.push(object);
+            }
+        }
+
+        cache.continuation_token = response.next_continuation_token().map(|t| t.to_string());
+        if !response.is_truncated() {
+            break;
+        }
+
+        if let Some(directory) = listing_cache {
+            if let Err(err) = cache.save(directory) {
+                warn!("Unable to update the listing cache: {}", err);
+            }
+        }
+    }
+
+    // A completed listing is not resumable so it's only worth keeping while it can be reused
+    if let Some(directory) = listing_cache {
+        let result = match ttl {
+            Some(_) => {
+                cache.completed_at = Some(unix_timestamp());
+                cache.save(directory)
+            }
+            None => ListingCache::clear(directory, client.bucket()),
+        };
+
+        if let Err(err) = result {
+            warn!("Unable to update the listing cache: {}", err);
+        }
+    }
+
+    Ok(cache.objects)
+}
+
+/// Adds this run's uploads to a reusable listing so later runs within the TTL don't upload them again
+fn refresh_listing_cache(
+    directory: &Path,
+    bucket: &str,
+    objects: &HashMap<Vec<String>, RemoteObject>,
+) {
+    let mut cache = ListingCache::load(directory, bucket);
+    if cache.completed_at.is_none() {
+        return;
+    }
+
+    cache.replace_objects(objects.values().cloned().collect());
+    if let Err(err) = cache.save(directory) {
+        warn!("Unable to update the listing cache: {}", err);
+    }
+}
+
+fn expand_path(input: PathBuf) -> BackupResult<PathBuf> {
+    let expanded_path: String = shellexpand::tilde(&parse_path(input)?).to_string();
+    Ok(Path::new(&expanded_path).to_owned())
+}
+
+fn split_filename(filename: &str) -> Vec<String> {
+    filename
+        .split(keys::SEPARATORS)
+        .map(|s| s.to_string())
+        .collect()
+}
+
+#[async_recursion]
+async fn traverse_directories(
+    path: &Path,
+    root: &Path,
+    client: &S3Client,
+    args: &CLIopts,
+    state: &mut RunState,
+) -> BackupResult<()> {
+    if is_excluded(path, root, &state.excludes) {
+        debug!("Excluding {:?}", path);
+        record_skip(&mut state.skipped_report, path, SkipReason::Excluded);
+        return Ok(());
+    }
+
+    // We use metadata since path::is_file() coerces an error into false
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            handle_permission_denied(path, args.on_permission_denied, &mut state.summary);
+            record_skip(
+                &mut state.skipped_report,
+                path,
+                SkipReason::PermissionDenied,
+            );
+            return Ok(());
+        }
+        Err(err) => {
+            warn!("Unable to read the metadata for {:?}: {}", path, err);
+            return Ok(());
+        }
+    };
+
+    if metadata.is_file() {
+        let file = match decide_file(
+            path,
+            &metadata,
+            root,
+            args,
+            &state.existing_files,
+            &state.excludes,
+            &mut state.claims,
+        ) {
+            Ok(file) => file,
+            Err(reason) => {
+                match reason {
+                    SkipReason::Filtered => {
+                        debug!("Skipping {:?} based on the file filters", path)
+                    }
+                    SkipReason::Empty => {
+                        debug!("Skipping empty file: {:?}", path);
+                        state.summary.empty_skipped += 1;
+                    }
+                    _ => {}
+                }
+                record_skip(&mut state.skipped_report, path, reason);
+                return Ok(());
+            }
+        };
+        let FileDecision {
+            relative_path,
+            original_path,
+            stripped_path,
+            verdict,
+        } = file;
+
+        if state.progress_signal.take() {
+            report_progress(state, path);
+        }
+
+        if let Some(progress_file) = &mut state.progress_file {
+            if let Err(err) = progress_file.update(&state.summary) {
+                warn!("Unable to write the progress file: {}", err);
+            }
+        }
+
+        debug!("Processing {:?}", path.file_name());
+        let filename_segments = split_filename(&stripped_path);
+        state.summary.candidates += 1;
+
+        if args.flag_insecure_perms {
+            if let Some(reason) = insecure_permissions(&metadata) {
+                warn!("Insecure permissions on {}: {}", stripped_path, reason);
+                state
+                    .summary
+                    .insecure_files
+                    .push((relative_path.clone(), reason));
+            }
+        }
+
+        let (remote, change) = match verdict {
+            Verdict::New => {
+                info!("Uploading new file: {}", stripped_path);
+                (None, Change::Content)
+            }
+            Verdict::Incomplete => {
+                info!("Re-uploading incomplete file: {}", stripped_path);
+                (None, Change::Content)
+            }
+            Verdict::Changed => {
+                info!("Uploading changed file: {}", stripped_path);
+                (None, Change::Content)
+            }
+            Verdict::Unchanged(remote) => (Some(remote), Change::Unchanged),
+            Verdict::Undecided(remote) => {
+                // Archived objects can't be copied onto themselves, so their metadata is left alone
+                let change = changes::detect_change(
+                    client,
+                    path,
+                    &metadata,
+                    &remote,
+                    args.checksum_over,
+                    args.update_metadata && !archive::is_archived(&remote),
+                )
+                .await;
+                if let Change::Content = change {
+                    info!("Uploading changed file: {}", stripped_path);
+                }
+                (Some(remote), change)
+            }
+        };
+
+        match (change, remote) {
+            (Change::Unchanged, Some(remote)) => {
+                let backfilled = if args.force_refresh_metadata && !archive::is_archived(&remote) {
+                    changes::backfill_metadata(client, path, &remote).await
+                } else {
+                    Ok(false)
+                };
 
-    let backup_client = S3Client::new(
-        args.bucket_backup,
-        args.region_backup,
-        &args.storage_class,
-        &args.encryption,
-    )
-    .await
-    .unwrap_or_else(|err| panic!("Unable to establish S3 client: {}", err));
+                match backfilled {
+                    Ok(true) => {
+                        info!(
+                            "Added missing metadata to unchanged file: {}",
+                            stripped_path
+                        );
+                        state.summary.metadata_updated += 1;
+                    }
+                    Ok(false) => {
+                        info!("Skipping existing file: {}", stripped_path);
+                        state.summary.skipped += 1;
+                        record_skip(&mut state.skipped_report, path, SkipReason::Unchanged);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to add the missing metadata to {}: {}",
+                            stripped_path, err
+                        );
+                        state
+                            .summary
+                            .failed
+                            .push(path.to_string_lossy().into_owned());
+                        return Ok(());
+                    }
+                }
+                record_checkpoint(&mut state.checkpoint, &remote);
+                return Ok(());
+            }
+            (Change::Metadata(attributes), Some(remote)) => {
+                info!("Updating metadata of unchanged file: {}", stripped_path);
+                match client.update_metadata(&remote.key, &attributes).await {
+                    Ok(_) => {
+                        state.summary.metadata_updated += 1;
+                        record_checkpoint(&mut state.checkpoint, &remote);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to update the metadata of {}: {}",
+                            stripped_path, err
+                        );
+                        state
+                            .summary
+                            .failed
+                            .push(path.to_string_lossy().into_owned());
+                    }
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
 
-    info!("Starting upload process");
-    upload_to_client(&client, args.path.clone()).await;
+        if let Some(pause) = state.pause_between_files {
+            tokio::time::sleep(pause).await;
+        }
 
-    info!("Starting upload process for backups");
-    upload_to_client(&backup_client, args.path).await;
-}
+        if let Some(minimum) = args.min_free_space {
+            disk_space::ensure_free_space(&std::env::temp_dir(), minimum)?;
+        }
 
-async fn upload_to_client(client: &S3Client, path: PathBuf) {
-    let mut files_by_path = fetch_existing_objects(client)
-    .await
-    .unwrap();
+        let file_metadata = match FileMetadata::from_file(path) {
+            Ok(m) => m,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                handle_permission_denied(path, args.on_permission_denied, &mut state.summary);
+                return Ok(());
+            }
+            Err(err) => {
+                error!("Failed to hash file {:?}: {}", stripped_path, err);
+                return Ok(());
+            }
+        };
 
-    info!("Found {} objects", files_by_path.len());
+        let mut object_metadata = file_metadata.to_object();
+        if let Some(original_path) = &original_path {
+            object_metadata.insert(ORIGINAL_PATH_KEY.to_owned(), encode_key(original_path));
+        }
+
+        if args.preserve_birthtime {
+            if let Some(birthtime) = birthtime(&metadata) {
+                object_metadata.insert(BIRTHTIME_KEY.to_owned(), birthtime.to_string());
+            }
+        }
 
-    let root = expand_path(path).unwrap_or_else(|err| panic!("Failed to read root path: {}", err));
+        if args.dedupe_within_run || args.dedupe_index.is_some() {
+            if let Some(source_key) = dedupe_source(client, state, &file_metadata.sha256).await {
+                info!("Copying {} from identical {}", stripped_path, source_key);
+                match client
+                    .copy_file(
+                        &source_key,
+                        &keys::to_key(&stripped_path),
+                        object_metadata.clone(),
+                    )
+                    .await
+                {
+                    Ok(output) => {
+                        state.summary.copied += 1;
+                        state
+                            .written_hashes
+                            .insert(keys::to_key(&stripped_path), file_metadata.sha256.clone());
 
-    let second = root.clone();
-    match traverse_directories(&root, &second, &mut files_by_path, client).await {
-        Ok(()) => info!("All directories synced"),
-        Err(err) => error!("Failed to sync directories: {}", err),
+                        if args.checksums_file.is_some() {
+                            state
+                                .checksums
+                                .push((keys::to_key(&relative_path), file_metadata.sha256.clone()));
+                        }
+
+                        let e_tag = output
+                            .copy_object_result()
+                            .and_then(|result| result.e_tag());
+                        let object = uploaded_object(client, &stripped_path, &metadata, e_tag);
+                        record_upload(state, filename_segments, object);
+                        return Ok(());
+                    }
+                    Err(err) => warn!(
+                        "Uploading {} since copying it from {} failed: {}",
+                        stripped_path, source_key, err
+                    ),
+                }
+            }
+        }
+
+        let mut retries = 0;
+        let output = loop {
+            // The body is consumed by every attempt so it's read from disk again for each of them
+            let data = match ByteStream::from_path(path).await {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("Failed to read file {:?}: {}", stripped_path, err);
+                    return Ok(());
+                }
+            };
+
+            let attempt_started = Instant::now();
+            let result = client
+                .upload_file(data, stripped_path.as_ref(), object_metadata.clone())
+                .await;
+            track_connection(client, &result, args, state).await;
+
+            match result {
+                Ok(output) => {
+                    if let Some(throughput) = &mut state.throughput {
+                        throughput.record_upload(metadata.len(), attempt_started.elapsed());
+                    }
+                    break output;
+                }
+                // The credentials are refreshed ahead of their expiry, so expired ones couldn't be
+                // refreshed and every following upload would fail as well. The run stops here.
+                Err(err) if err.is_expired_credentials() => {
+                    error!("Failed to upload {}: {}", stripped_path, err);
+                    state
+                        .summary
+                        .failed
+                        .push(path.to_string_lossy().into_owned());
+                    return Err(BackupError::CredentialsUnavailable(
+                        "the credentials expired and couldn't be refreshed".to_owned(),
+                    ));
+                }
+                Err(err) if err.is_retryable() && state.retry_budget.try_acquire(retries) => {
+                    retries += 1;
+                    client.telemetry().record_retry(err.retry_kind());
+                    warn!(
+                        "Retrying upload of {} ({} of {}): {}",
+                        stripped_path, retries, args.retries_per_file, err
+                    );
+                    tokio::time::sleep(retry::delay(retries, err.retry_after())).await;
+                }
+                Err(err) => {
+                    error!("Failed to upload {}: {}", stripped_path, err);
+                    state
+                        .summary
+                        .failed
+                        .push(path.to_string_lossy().into_owned());
+                    return Ok(());
+                }
+            }
+        };
+
+        if args.verify_after_upload {
+            let key = keys::to_key(&stripped_path);
+            match verify::first_mismatch(client, &key, path, args.verify_chunk_size).await {
+                Ok(None) => debug!("Verified upload of {}", stripped_path),
+                Ok(Some(offset)) => {
+                    error!(
+                        "The upload of {} differs from the local file at byte {}",
+                        stripped_path, offset
+                    );
+                    state
+                        .summary
+                        .failed
+                        .push(path.to_string_lossy().into_owned());
+                    return Ok(());
+                }
+                Err(err) => {
+                    error!("Failed to verify the upload of {}: {}", stripped_path, err);
+                    state
+                        .summary
+                        .failed
+                        .push(path.to_string_lossy().into_owned());
+                    return Ok(());
+                }
+            }
+        }
+
+        state.summary.uploaded += 1;
+        state.summary.bytes_uploaded += metadata.len();
+        state
+            .summary
+            .record_directory(&split_filename(&relative_path), metadata.len());
+        if let Ok(modified) = metadata.modified() {
+            state.summary.record_age(modified);
+        }
+
+        let object = uploaded_object(client, &stripped_path, &metadata, output.e_tag());
+        record_upload(state, filename_segments, object);
+
+        if args.checksums_file.is_some() {
+            state
+                .checksums
+                .push((keys::to_key(&relative_path), file_metadata.sha256.clone()));
+        }
+
+        if args.dedupe_within_run || args.dedupe_index.is_some() {
+            state
+                .written_hashes
+                .insert(keys::to_key(&stripped_path), file_metadata.sha256.clone());
+            state
+                .uploaded_by_hash
+                .insert(file_metadata.sha256, keys::to_key(&stripped_path));
+        }
+        return Ok(());
+    }
+
+    if args.walk_symlinked_dirs_once && already_walked(path, &mut state.claims.walked_directories) {
+        record_skip(&mut state.skipped_report, path, SkipReason::AlreadyWalked);
+        return Ok(());
+    }
+
+    if !metadata.is_dir() {
+        debug!("Skipping special file {:?}", path);
+        record_skip(&mut state.skipped_report, path, SkipReason::Special);
+        return Ok(());
+    }
+
+    debug!("Diving into new directory: {:?}", path);
+
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            handle_permission_denied(path, args.on_permission_denied, &mut state.summary);
+            record_skip(
+                &mut state.skipped_report,
+                path,
+                SkipReason::PermissionDenied,
+            );
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Failed to read directory {:?}: {}", path, err);
+            return Ok(());
+        }
+    };
+
+    for entry in sorted_entries(entries, args) {
+        let directory_name = parse_path(entry.clone())?;
+
+        info!("Evaluating {}", directory_name);
+        traverse_directories(&entry, root, client, args, state).await?;
     }
+
+    Ok(())
 }
 
-async fn fetch_existing_objects(client: &S3Client) -> BackupResult<HashSet<Vec<String>>> {
-    let mut files_by_path = HashSet::<Vec<String>>::new();
-    let mut next_token: Option<String> = None;
+/// Places a relative path under the prefix, or in the key template
+fn render_key(relative_path: &str, args: &CLIopts) -> String {
+    let prefix = args.prefix.as_deref().unwrap_or_default();
+    match &args.key_template {
+        Some(template) => template.render(prefix, relative_path),
+        None if prefix.is_empty() => relative_path.to_owned(),
+        None => format!("{}/{}", prefix.trim_end_matches('/'), relative_path),
+    }
+}
 
-    loop {
-        let response = client.fetch_existing_objects(next_token).await?;
-        for object in response.contents().unwrap_or_default() {
-            let filename = object.key().expect("No filename found!");
+/// The path of a file relative to the root, which identifies it in the reports, along with its
+/// original path when it had to be sanitized
+fn resolve_relative_path(
+    path: &Path,
+    root: &Path,
+    args: &CLIopts,
+) -> Option<(String, Option<String>)> {
+    let stripped_path = strip_path(path, root)?;
+    let stripped_path = match args.strip_components {
+        0 => stripped_path,
+        count => match keys::strip_components(&stripped_path, count) {
+            Some(p) => p,
+            None => {
+                warn!(
+                    "Skipping {:?} since it has no more than {} components to strip",
+                    stripped_path, count
+                );
+                return None;
+            }
+        },
+    };
+
+    if !keys::has_problematic_characters(&stripped_path, args.safe_key_charset) {
+        return Some((stripped_path, None));
+    }
 
-            let filename_pieces = split_filename(filename);
-            files_by_path.insert(filename_pieces);
+    match args.on_problematic_key {
+        ProblematicKeyPolicy::Keep => Some((stripped_path, None)),
+        ProblematicKeyPolicy::Skip => {
+            warn!("Skipping file with a problematic name: {:?}", stripped_path);
+            None
         }
+        ProblematicKeyPolicy::Sanitize => {
+            let sanitized = keys::sanitize(&stripped_path, args.safe_key_charset);
+            warn!("Sanitized {:?} to {:?}", stripped_path, sanitized);
+            Some((sanitized, Some(stripped_path)))
+        }
+    }
+}
 
-        next_token = response.next_continuation_token().map(|t| t.to_string());
-        if !response.is_truncated() {
-            return Ok(files_by_path);
+/// The key to copy identical content from. Entries of the shared index may point at keys which were
+/// deleted or overwritten with other content since, so those are checked before copying.
+async fn dedupe_source(client: &S3Client, state: &RunState, sha256: &str) -> Option<String> {
+    let source_key = state.uploaded_by_hash.get(sha256)?;
+    match state.written_hashes.get(source_key) {
+        Some(written) if written == sha256 => return Some(source_key.clone()),
+        Some(_) => return None,
+        None => {}
+    }
+
+    match dedupe_index::holds_content(client, source_key, sha256).await {
+        Ok(true) => Some(source_key.clone()),
+        Ok(false) => {
+            info!("Not copying from {} since its content changed", source_key);
+            None
+        }
+        Err(err) => {
+            warn!("Unable to check the content of {}: {}", source_key, err);
+            None
         }
     }
 }
 
-fn expand_path(input: PathBuf) -> BackupResult<PathBuf> {
-    let expanded_path: String = shellexpand::tilde(&parse_path(input)?).to_string();
-    return Ok(Path::new(&expanded_path).to_owned());
+/// Sorted so colliding keys get the same suffix on every run, with genuine names first so they keep
+/// their key when a sanitized name collides
+fn sorted_entries(entries: fs::ReadDir, args: &CLIopts) -> Vec<PathBuf> {
+    let mut entries: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+    entries.sort_by_cached_key(|entry| {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        (
+            keys::has_problematic_characters(&name, args.safe_key_charset),
+            name.into_owned(),
+        )
+    });
+    entries
 }
 
-fn split_filename(filename: &str) -> Vec<String> {
-    return filename
-        .split(&['/', '\\'][..])
-        .map(|s| s.to_string())
-        .collect();
+/// How a file compares to its object, as far as can be told without fetching the object
+#[derive(Debug)]
+enum Verdict {
+    New,
+    /// An interrupted upload left an empty object behind
+    Incomplete,
+    Changed,
+    Unchanged(RemoteObject),
+    /// Telling requires the checksum or metadata stored with the object
+    Undecided(RemoteObject),
 }
 
-#[async_recursion]
-async fn traverse_directories(
+/// A file the walk goes on to compare or upload, under the key it claimed
+#[derive(Debug)]
+struct FileDecision {
+    relative_path: String,
+    original_path: Option<String>,
+    stripped_path: String,
+    verdict: Verdict,
+}
+
+/// Decides what a walk does with a file, shared by the plan and the upload so the plan matches
+/// what is uploaded. The key is claimed, so every file is decided once per walk.
+fn decide_file(
     path: &Path,
+    metadata: &fs::Metadata,
     root: &Path,
-    existing_files: &mut HashSet<Vec<String>>,
-    client: &S3Client,
-) -> BackupResult<()> {
-    // We use metadata since path::is_file() coerces an error into false
+    args: &CLIopts,
+    existing_files: &HashMap<Vec<String>, RemoteObject>,
+    excludes: &Excludes,
+    claims: &mut WalkClaims,
+) -> Result<FileDecision, SkipReason> {
+    if excludes.skips_file(path) {
+        return Err(SkipReason::Filtered);
+    }
+
+    let (relative_path, original_path) =
+        resolve_relative_path(path, root, args).ok_or(SkipReason::UnusableKey)?;
+    // Every key of a run shares its prefix and template, so collisions are resolved on the
+    // relative path, which keeps the suffix on the file name
+    let (relative_path, original_path) = match &mut claims.claimed_keys {
+        Some(claimed_keys) => avoid_sanitized_collision(relative_path, original_path, claimed_keys),
+        None => (relative_path, original_path),
+    };
+    let (relative_path, original_path) = match &mut claims.folded_keys {
+        Some(folded_keys) => avoid_case_collision(relative_path, original_path, folded_keys, args),
+        None => (relative_path, original_path),
+    };
+    let stripped_path = render_key(&relative_path, args);
+
+    if skips_empty_file(args, metadata.len()) {
+        return Err(SkipReason::Empty);
+    }
+
+    let verdict = match existing_files.get(&split_filename(&stripped_path)) {
+        None => Verdict::New,
+        Some(remote) if is_incomplete_upload(remote, metadata.len()) => Verdict::Incomplete,
+        Some(remote) => {
+            // The ETags of objects encrypted with aws:kms aren't based on their content
+            let etag_changed = if args.compare_etag && args.encryption != "aws:kms" {
+                changes::etag_changed(path, metadata, remote, args.etag_part_size)
+            } else {
+                None
+            };
+
+            // Archived objects can't be copied onto themselves, so their metadata is left alone
+            let update_metadata = args.update_metadata && !archive::is_archived(remote);
+            if etag_changed == Some(true) {
+                Verdict::Changed
+            } else if etag_changed == Some(false) && !update_metadata {
+                Verdict::Unchanged(remote.clone())
+            } else if args.checksum_over.is_some() || update_metadata {
+                Verdict::Undecided(remote.clone())
+            } else {
+                Verdict::Unchanged(remote.clone())
+            }
+        }
+    };
+
+    Ok(FileDecision {
+        relative_path,
+        original_path,
+        stripped_path,
+        verdict,
+    })
+}
+
+/// Walks the tree like `traverse_directories` but only tallies what would be uploaded
+fn plan_directories(
+    path: &Path,
+    root: &Path,
+    existing_files: &HashMap<Vec<String>, RemoteObject>,
+    args: &CLIopts,
+    excludes: &Excludes,
+    claims: &mut WalkClaims,
+    plan: &mut Plan,
+) {
+    if is_excluded(path, root, excludes) {
+        return;
+    }
+
     let metadata = match fs::metadata(path) {
         Ok(m) => m,
-        Err(err) => {
-            warn!("Unable to read the metadata for {:?}: {}", path, err);
-            return Ok(());
-        }
+        Err(_) => return,
     };
 
     if metadata.is_file() {
-        debug!("Processing {:?}", path.file_name());
-        let stripped_path = match strip_path(path, root) {
-            Some(p) => p,
-            None => return Ok(()),
+        let file = match decide_file(
+            path,
+            &metadata,
+            root,
+            args,
+            existing_files,
+            excludes,
+            claims,
+        ) {
+            Ok(file) => file,
+            Err(_) => return,
         };
-        let filename_segments = split_filename(&stripped_path);
 
-        if existing_files.contains(&filename_segments) {
-            info!("Skipping existing file: {}", stripped_path);
-            return Ok(());
+        match file.verdict {
+            Verdict::New => plan.new_files += 1,
+            Verdict::Incomplete | Verdict::Changed => plan.changed_files += 1,
+            // Planning doesn't fetch the checksum, so it guesses from the size and modification time
+            Verdict::Undecided(remote)
+                if args.checksum_over.is_some()
+                    && changes::modified_since_upload(&metadata, &remote) =>
+            {
+                plan.changed_files += 1
+            }
+            Verdict::Unchanged(_) | Verdict::Undecided(_) => {
+                plan.unchanged_files += 1;
+                return;
+            }
+        }
+
+        plan.bytes += metadata.len();
+        plan.record_prefix(&split_filename(&file.stripped_path));
+        if args.budget.is_some() {
+            plan.files.push(PlannedFile {
+                key: keys::to_key(&file.stripped_path),
+                bytes: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
         }
+        return;
+    }
 
-        info!("Uploading new file: {}", stripped_path);
-        existing_files.insert(filename_segments);
+    if args.walk_symlinked_dirs_once && already_walked(path, &mut claims.walked_directories) {
+        return;
+    }
 
-        let file_data = ByteStream::from_path(path).await;
-        match file_data {
-            Ok(data) => {
-                client.upload_file(data, stripped_path.as_ref()).await?;
-            }
-            Err(err) => {
-                error!("Failed to read file {:?}: {}", stripped_path, err);
-            }
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in sorted_entries(entries, args) {
+            plan_directories(&entry, root, existing_files, args, excludes, claims, plan);
         }
-        return Ok(());
+    }
+}
+
+/// Suffixes a sanitized key which was already used by another file, e.g. `a ` sanitized to `a` next
+/// to a file which is called `a`
+fn avoid_sanitized_collision(
+    key: String,
+    original_path: Option<String>,
+    claimed_keys: &mut HashSet<String>,
+) -> (String, Option<String>) {
+    if original_path.is_none() || !claimed_keys.contains(&key) {
+        claimed_keys.insert(key.clone());
+        return (key, original_path);
     }
 
-    debug!("Diving into new directory: {:?}", path);
+    let suffixed = (1..)
+        .map(|n| keys::with_conflict_suffix(&key, n))
+        .find(|candidate| claimed_keys.insert(candidate.clone()))
+        .expect("Ran out of conflict suffixes");
+    warn!(
+        "Uploading {:?} as {:?} since its sanitized key is used by another file",
+        original_path.as_deref().unwrap_or_default(),
+        suffixed
+    );
 
-    for entry in fs::read_dir(path).unwrap().flatten() {
-        let directory_name = parse_path(entry.path())?;
+    (suffixed, original_path)
+}
 
-        info!("Evaluating {}", directory_name);
-        traverse_directories(&entry.path(), root, existing_files, client).await?;
+/// Gives a path whose key only differs in case from the key of another file, or of an object which
+/// is already in the bucket, a conflict suffix, recording the original path like a sanitized key
+fn avoid_case_collision(
+    relative_path: String,
+    original_path: Option<String>,
+    folded_keys: &mut FoldedKeys,
+    args: &CLIopts,
+) -> (String, Option<String>) {
+    if folded_keys.claim(&render_key(&relative_path, args)) {
+        return (relative_path, original_path);
     }
 
-    Ok(())
+    let suffixed = (1..)
+        .map(|n| keys::with_conflict_suffix(&relative_path, n))
+        .find(|candidate| folded_keys.claim(&render_key(candidate, args)))
+        .expect("Ran out of conflict suffixes");
+    warn!(
+        "Uploading {:?} as {:?} since its key only differs in case from another file or object",
+        relative_path, suffixed
+    );
+
+    (suffixed, original_path.or(Some(relative_path)))
+}
+
+/// Whether the real directory behind the path was walked before, through a symlink or otherwise
+fn already_walked(path: &Path, walked_directories: &mut HashSet<PathBuf>) -> bool {
+    match fs::canonicalize(path) {
+        Ok(real_path) if !walked_directories.insert(real_path.clone()) => {
+            info!(
+                "Skipping {:?} since {:?} was already backed up through another path",
+                path, real_path
+            );
+            true
+        }
+        Ok(_) => false,
+        Err(err) => {
+            warn!("Unable to resolve the real path of {:?}: {}", path, err);
+            false
+        }
+    }
+}
+
+fn is_excluded(path: &Path, root: &Path, excludes: &Excludes) -> bool {
+    path.strip_prefix(root)
+        .is_ok_and(|relative| excludes.is_excluded(relative))
+}
+
+/// Rebuilds the client after too many consecutive connection failures, so a long run can recover
+/// from a network outage
+async fn track_connection<T>(
+    client: &S3Client,
+    result: &BackupResult<T>,
+    args: &CLIopts,
+    state: &mut RunState,
+) {
+    let connection_failed = matches!(result, Err(err) if err.is_connection_failure());
+    if needs_reconnect(&mut state.connection_failures, connection_failed, args) {
+        warn!(
+            "Reconnecting to {} after {} consecutive connection failures",
+            client.bucket(),
+            state.connection_failures
+        );
+        client.reconnect().await;
+        state.connection_failures = 0;
+    }
+}
+
+/// Counts consecutive connection failures, which any other outcome resets
+fn needs_reconnect(connection_failures: &mut u32, connection_failed: bool, args: &CLIopts) -> bool {
+    if !connection_failed {
+        *connection_failures = 0;
+        return false;
+    }
+
+    *connection_failures += 1;
+    args.reconnect_after > 0 && *connection_failures >= args.reconnect_after
+}
+
+fn report_progress(state: &RunState, current: &Path) {
+    let summary = &state.summary;
+    info!(
+        "Processed {} files: {} uploaded, {} skipped, {} failed, {} transferred; now at {:?}",
+        summary.candidates,
+        summary.uploaded,
+        summary.skipped,
+        summary.failed.len(),
+        format_bytes(summary.bytes_uploaded as f64),
+        current
+    );
+
+    if let Some(checkpoint) = &state.checkpoint {
+        match checkpoint.sync() {
+            Ok(()) => info!("Flushed the checkpoint"),
+            Err(err) => warn!("Unable to flush the checkpoint: {}", err),
+        }
+    }
+}
+
+fn record_skip(skipped_report: &mut Option<SkippedReport>, path: &Path, reason: SkipReason) {
+    if let Some(skipped_report) = skipped_report {
+        if let Err(err) = skipped_report.record(path, reason) {
+            warn!("Unable to update the skipped report: {}", err);
+        }
+    }
+}
+
+fn uploaded_object(
+    client: &S3Client,
+    stripped_path: &str,
+    metadata: &fs::Metadata,
+    e_tag: Option<&str>,
+) -> RemoteObject {
+    RemoteObject {
+        key: keys::to_key(stripped_path),
+        size: metadata.len() as i64,
+        last_modified: Some(unix_timestamp()),
+        e_tag: e_tag.map(str::to_owned),
+        storage_class: Some(client.storage_class().to_owned()),
+    }
+}
+
+/// Records a file once its object was uploaded or copied, so the checkpoint, the inventory and the
+/// listing cache only ever contain objects which exist
+fn record_upload(state: &mut RunState, filename_segments: Vec<String>, object: RemoteObject) {
+    record_checkpoint(&mut state.checkpoint, &object);
+    state.existing_files.insert(filename_segments, object);
+}
+
+fn record_checkpoint(checkpoint: &mut Option<Checkpoint>, object: &RemoteObject) {
+    if let Some(checkpoint) = checkpoint {
+        if let Err(err) = checkpoint.record(object) {
+            warn!("Unable to update the checkpoint: {}", err);
+        }
+    }
+}
+
+fn handle_permission_denied(path: &Path, policy: PermissionDeniedPolicy, summary: &mut Summary) {
+    match policy {
+        PermissionDeniedPolicy::Warn => warn!("Permission denied reading {:?}", path),
+        PermissionDeniedPolicy::Skip => debug!("Permission denied reading {:?}", path),
+        PermissionDeniedPolicy::Fail => {
+            error!("Permission denied reading {:?}", path);
+            summary.failed.push(path.to_string_lossy().into_owned());
+        }
+    }
+}
+
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
 }
 
 fn parse_path(path: PathBuf) -> BackupResult<String> {
@@ -174,3 +1954,332 @@ fn strip_path(path: &Path, root: &Path) -> Option<String> {
 
     Some(path.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(extra: &[&str]) -> CLIopts {
+        let required = [
+            "backup-rs",
+            "--bucket",
+            "bucket",
+            "--bucket-backup",
+            "backup",
+        ];
+        CLIopts::from_iter(required.iter().chain(extra).chain(&["/source"]))
+    }
+
+    fn remote_object(key: &str, size: i64) -> RemoteObject {
+        RemoteObject {
+            key: key.to_owned(),
+            size,
+            last_modified: None,
+            e_tag: None,
+            storage_class: None,
+        }
+    }
+
+    #[test]
+    fn only_the_fail_policy_fails_unreadable_files() {
+        let path = Path::new("private/key.pem");
+        for (policy, failed) in [
+            (PermissionDeniedPolicy::Warn, 0),
+            (PermissionDeniedPolicy::Skip, 0),
+            (PermissionDeniedPolicy::Fail, 1),
+        ] {
+            let mut summary = Summary::default();
+            handle_permission_denied(path, policy, &mut summary);
+            assert_eq!(summary.failed.len(), failed, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn run_without_files_only_fails_when_asked_to() {
+        let empty = Summary::default();
+        let non_empty = Summary {
+            candidates: 1,
+            ..Summary::default()
+        };
+
+        assert!(is_unexpectedly_empty(
+            &options(&["--fail-on-empty"]),
+            &empty
+        ));
+        assert!(!is_unexpectedly_empty(
+            &options(&["--fail-on-empty"]),
+            &non_empty
+        ));
+        assert!(!is_unexpectedly_empty(&options(&[]), &empty));
+    }
+
+    #[test]
+    fn empty_object_of_a_non_empty_file_is_incomplete() {
+        assert!(is_incomplete_upload(&remote_object("a", 0), 10));
+        assert!(!is_incomplete_upload(&remote_object("a", 0), 0));
+        assert!(!is_incomplete_upload(&remote_object("a", 10), 10));
+    }
+
+    #[test]
+    fn reconnect_follows_consecutive_connection_failures() {
+        let args = options(&["--reconnect-after", "2"]);
+        let mut connection_failures = 0;
+
+        assert!(!needs_reconnect(&mut connection_failures, true, &args));
+        assert!(!needs_reconnect(&mut connection_failures, false, &args));
+        assert!(!needs_reconnect(&mut connection_failures, true, &args));
+        assert!(needs_reconnect(&mut connection_failures, true, &args));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_reached_through_several_symlinks_is_walked_once() {
+        let root =
+            std::env::temp_dir().join(format!("backup-rs-main-symlinks-{}", std::process::id()));
+        fs::create_dir_all(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("first")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("second")).unwrap();
+
+        let mut walked_directories = HashSet::new();
+        assert!(!already_walked(
+            &root.join("first"),
+            &mut walked_directories
+        ));
+        assert!(already_walked(
+            &root.join("second"),
+            &mut walked_directories
+        ));
+        assert!(already_walked(&root.join("real"), &mut walked_directories));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn file_name_with_a_backslash_is_a_single_segment() {
+        assert_eq!(split_filename("dir/a\\b.txt"), ["dir", "a\\b.txt"]);
+    }
+
+    #[test]
+    fn dedupe_index_isnt_a_backed_up_file() {
+        let args = options(&["--dedupe-index", "shared/index.json"]);
+
+        assert!(is_dedupe_index(
+            &remote_object("shared/index.json", 1),
+            &args
+        ));
+        assert!(!is_dedupe_index(&remote_object("shared/a.txt", 1), &args));
+        assert!(!is_dedupe_index(
+            &remote_object("shared/index.json", 1),
+            &options(&[])
+        ));
+    }
+
+    #[test]
+    fn root_without_the_marker_is_refused() {
+        let root =
+            std::env::temp_dir().join(format!("backup-rs-main-marker-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let args = options(&["--require-marker", ".backup-root"]);
+
+        assert_eq!(
+            missing_marker(&args, &root),
+            Some(Path::new(".backup-root"))
+        );
+        fs::write(root.join(".backup-root"), b"").unwrap();
+        assert_eq!(missing_marker(&args, &root), None);
+        assert_eq!(
+            missing_marker(&options(&[]), Path::new("/nonexistent")),
+            None
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failing_pre_command_aborts_the_run_but_still_cleans_up() {
+        let cleaned_up =
+            std::env::temp_dir().join(format!("backup-rs-main-pre-{}", std::process::id()));
+        let post_command = format!("touch {}", cleaned_up.display());
+        let args = options(&["--pre-command", "exit 1", "--post-command", &post_command]);
+        let mut ran = false;
+
+        assert!(
+            !run_between_hooks(&args, async {
+                ran = true;
+                true
+            })
+            .await
+        );
+        assert!(!ran);
+        assert!(cleaned_up.exists());
+        fs::remove_file(cleaned_up).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_command_runs_after_a_failed_run() {
+        let cleaned_up =
+            std::env::temp_dir().join(format!("backup-rs-main-post-{}", std::process::id()));
+        let post_command = format!("touch {}", cleaned_up.display());
+        let args = options(&["--pre-command", "exit 0", "--post-command", &post_command]);
+
+        assert!(!run_between_hooks(&args, async { false }).await);
+        assert!(cleaned_up.exists());
+        fs::remove_file(&cleaned_up).unwrap();
+
+        let args = options(&["--post-command", "exit 1"]);
+        assert!(!run_between_hooks(&args, async { true }).await);
+    }
+
+    #[test]
+    fn only_more_new_files_than_the_limit_exceed_it() {
+        let plan = Plan {
+            new_files: 10,
+            ..Plan::default()
+        };
+
+        assert!(exceeds_new_file_limit(
+            &options(&["--max-new-files", "9"]),
+            &plan
+        ));
+        assert!(!exceeds_new_file_limit(
+            &options(&["--max-new-files", "10"]),
+            &plan
+        ));
+        assert!(!exceeds_new_file_limit(&options(&[]), &plan));
+    }
+
+    #[test]
+    fn empty_files_are_only_skipped_when_asked_to() {
+        assert!(skips_empty_file(&options(&["--skip-empty-files"]), 0));
+        assert!(!skips_empty_file(&options(&["--skip-empty-files"]), 1));
+        assert!(!skips_empty_file(&options(&[]), 0));
+    }
+
+    #[test]
+    fn sanitized_key_doesnt_take_the_key_of_another_file() {
+        let mut claimed_keys = HashSet::new();
+        let genuine = avoid_sanitized_collision("dir/a".to_owned(), None, &mut claimed_keys);
+        let sanitized = avoid_sanitized_collision(
+            "dir/a".to_owned(),
+            Some("dir/a ".to_owned()),
+            &mut claimed_keys,
+        );
+
+        assert_eq!(genuine, ("dir/a".to_owned(), None));
+        assert_eq!(sanitized, ("dir/a~1".to_owned(), Some("dir/a ".to_owned())));
+    }
+
+    #[test]
+    fn sanitized_keys_which_collide_get_a_suffix() {
+        let mut claimed_keys = HashSet::new();
+        let names = ["é.txt", "😀.txt"].map(|name| {
+            let sanitized = keys::sanitize(name, true);
+            avoid_sanitized_collision(sanitized, Some(name.to_owned()), &mut claimed_keys).0
+        });
+
+        assert_eq!(names, ["_.txt", "_~1.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plan_walks_symlinked_directories_once_and_claims_sanitized_keys() {
+        let root = std::env::temp_dir().join(format!("backup-rs-main-plan-{}", std::process::id()));
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/a.txt"), b"a").unwrap();
+        fs::write(root.join("😀.txt"), b"b").unwrap();
+        fs::write(root.join("🎉.txt"), b"c").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        let plan = |args: &CLIopts| {
+            let mut plan = Plan::default();
+            let mut claims = WalkClaims::new(args, None);
+            let existing_files = HashMap::new();
+            let excludes = Excludes::new(&[], None).unwrap();
+            plan_directories(
+                &root,
+                &root,
+                &existing_files,
+                args,
+                &excludes,
+                &mut claims,
+                &mut plan,
+            );
+            plan
+        };
+
+        let args = options(&[
+            "--walk-symlinked-dirs-once",
+            "--safe-key-charset",
+            "--on-problematic-key",
+            "sanitize",
+            "--dry-run",
+            "--budget",
+            "100",
+        ]);
+        let planned = plan(&args);
+        let mut keys: Vec<_> = planned.files.iter().map(|file| file.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["_.txt", "_~1.txt", "link/a.txt"]);
+        assert_eq!(plan(&options(&[])).new_files, 4);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn files_are_decided_on_their_etag_before_fetching_the_object() {
+        let root =
+            std::env::temp_dir().join(format!("backup-rs-main-decide-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("a.txt");
+        fs::write(&path, b"content").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let excludes = Excludes::new(&[], None).unwrap();
+        let decide = |args: &[&str], e_tag: &str| {
+            let remote = RemoteObject {
+                e_tag: Some(format!("\"{}\"", e_tag)),
+                ..remote_object("a.txt", 7)
+            };
+            let existing_files = HashMap::from([(split_filename("a.txt"), remote)]);
+            let args = options(args);
+            let mut claims = WalkClaims::new(&args, None);
+            decide_file(
+                &path,
+                &metadata,
+                &root,
+                &args,
+                &existing_files,
+                &excludes,
+                &mut claims,
+            )
+            .unwrap()
+            .verdict
+        };
+        let e_tag = etag::compute(&path, None).unwrap();
+
+        assert!(matches!(
+            decide(&["--compare-etag"], &e_tag),
+            Verdict::Unchanged(_)
+        ));
+        assert!(matches!(decide(&["--compare-etag"], "0"), Verdict::Changed));
+        assert!(matches!(
+            decide(&["--compare-etag", "--update-metadata"], &e_tag),
+            Verdict::Undecided(_)
+        ));
+        assert!(matches!(decide(&[], "0"), Verdict::Unchanged(_)));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn summary_only_keeps_the_summary_and_errors() {
+        let filter = env_logger::filter::Builder::new()
+            .parse(SUMMARY_ONLY_FILTER)
+            .build();
+        let enabled = |level, target| {
+            filter.enabled(&log::Metadata::builder().level(level).target(target).build())
+        };
+
+        assert!(enabled(log::Level::Info, SUMMARY_TARGET));
+        assert!(enabled(log::Level::Error, "backup_rs"));
+        assert!(!enabled(log::Level::Warn, "backup_rs"));
+        assert!(!enabled(log::Level::Info, "backup_rs"));
+    }
+}
@@ -0,0 +1,251 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+const SIZE_KEY: &str = "size";
+const SHA256_KEY: &str = "sha256";
+const MODE_KEY: &str = "mode";
+
+/// Holds the URL-encoded path of a file whose key had to be sanitized
+pub const ORIGINAL_PATH_KEY: &str = "original-path";
+/// Holds the file's creation time in seconds since the epoch, when requested and available
+pub const BIRTHTIME_KEY: &str = "birthtime";
+
+/// File attributes stored alongside each object so restored files can be verified
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub sha256: String,
+    pub mode: Option<u32>,
+}
+
+impl FileMetadata {
+    pub fn from_file(path: &Path) -> io::Result<FileMetadata> {
+        let metadata = fs::metadata(path)?;
+
+        Ok(FileMetadata {
+            size: metadata.len(),
+            sha256: hash_file(path)?,
+            mode: file_mode(&metadata),
+        })
+    }
+
+    /// Reads the attributes back from an object's metadata, which is absent for objects we didn't upload
+    pub fn from_object(metadata: &HashMap<String, String>) -> Option<FileMetadata> {
+        Some(FileMetadata {
+            size: metadata.get(SIZE_KEY)?.parse().ok()?,
+            sha256: metadata.get(SHA256_KEY)?.to_owned(),
+            mode: metadata
+                .get(MODE_KEY)
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok()),
+        })
+    }
+
+    pub fn to_object(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert(SIZE_KEY.to_owned(), self.size.to_string());
+        metadata.insert(SHA256_KEY.to_owned(), self.sha256.to_owned());
+        if let Some(mode) = self.mode {
+            metadata.insert(MODE_KEY.to_owned(), format!("{:o}", mode));
+        }
+
+        metadata
+    }
+
+    /// Names the attributes which differ, ignoring the mode when either side doesn't track it
+    pub fn mismatches(&self, actual: &FileMetadata) -> Vec<&'static str> {
+        let mut mismatches = Vec::new();
+        if self.size != actual.size {
+            mismatches.push(SIZE_KEY);
+        }
+        if self.sha256 != actual.sha256 {
+            mismatches.push(SHA256_KEY);
+        }
+        if let (Some(expected), Some(found)) = (self.mode, actual.mode) {
+            if expected != found {
+                mismatches.push(MODE_KEY);
+            }
+        }
+
+        mismatches
+    }
+}
+
+/// S3 checksums are the base64 of the digest, which the metadata holds as hex
+pub fn s3_checksum(metadata: &HashMap<String, String>) -> Option<String> {
+    let hex = metadata.get(SHA256_KEY)?;
+    let digest = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(BASE64.encode(digest))
+}
+
+/// Turns an S3 checksum back into hex, or `None` for the checksums of multipart uploads, which
+/// are a checksum of the part checksums followed by `-` and the number of parts
+pub fn sha256_from_s3_checksum(checksum: &str) -> Option<String> {
+    let digest = BASE64.decode(checksum).ok()?;
+    Some(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Not every platform or filesystem records a creation time, in which case there's nothing to store
+pub fn birthtime(metadata: &fs::Metadata) -> Option<i64> {
+    metadata
+        .created()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs() as i64)
+}
+
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Describes why a file's permissions are unusual enough to flag during an audit
+#[cfg(unix)]
+pub fn insecure_permissions(metadata: &fs::Metadata) -> Option<&'static str> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+
+    if mode & 0o002 != 0 {
+        Some("writable by others")
+    } else if mode & 0o6000 != 0 {
+        Some("setuid or setgid")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn insecure_permissions(_metadata: &fs::Metadata) -> Option<&'static str> {
+    None
+}
+
+#[cfg(unix)]
+pub fn apply_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn apply_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_survives_the_round_trip_through_an_object() {
+        let metadata = FileMetadata {
+            size: 42,
+            sha256: "ab".repeat(32),
+            mode: Some(0o640),
+        };
+
+        let object = metadata.to_object();
+        assert_eq!(object.get(MODE_KEY).map(String::as_str), Some("640"));
+        assert_eq!(FileMetadata::from_object(&object), Some(metadata));
+    }
+
+    #[test]
+    fn objects_without_our_metadata_have_none() {
+        assert_eq!(FileMetadata::from_object(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn mode_is_only_compared_when_both_sides_have_one() {
+        let with_mode = |mode| FileMetadata {
+            size: 1,
+            sha256: "a".to_owned(),
+            mode,
+        };
+        let stored = with_mode(Some(0o644));
+
+        assert!(stored.mismatches(&with_mode(None)).is_empty());
+        assert_eq!(stored.mismatches(&with_mode(Some(0o600))), ["mode"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_writable_and_setuid_files_are_insecure() {
+        let path = std::env::temp_dir().join(format!("backup-rs-metadata-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let permissions = |mode| {
+            apply_mode(&path, mode).unwrap();
+            insecure_permissions(&fs::metadata(&path).unwrap())
+        };
+
+        assert_eq!(permissions(0o644), None);
+        assert_eq!(permissions(0o646), Some("writable by others"));
+        assert_eq!(permissions(0o4755), Some("setuid or setgid"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(any(target_os = "macos", windows))]
+    #[test]
+    fn birthtime_is_captured_where_supported() {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-metadata-birthtime-{}",
+            std::process::id()
+        ));
+        fs::write(&path, b"").unwrap();
+
+        assert!(birthtime(&fs::metadata(&path).unwrap()).is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn birthtime_is_absent_when_the_filesystem_has_none() {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-metadata-no-birthtime-{}",
+            std::process::id()
+        ));
+        fs::write(&path, b"").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+
+        assert_eq!(birthtime(&metadata).is_some(), metadata.created().is_ok());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn s3_checksum_is_the_base64_of_the_stored_hash() {
+        let empty_sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let empty_checksum = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let metadata = HashMap::from([(SHA256_KEY.to_owned(), empty_sha256.to_owned())]);
+
+        assert_eq!(s3_checksum(&metadata).as_deref(), Some(empty_checksum));
+        assert_eq!(
+            sha256_from_s3_checksum(empty_checksum).as_deref(),
+            Some(empty_sha256)
+        );
+    }
+
+    #[test]
+    fn multipart_checksums_have_no_sha256() {
+        assert_eq!(
+            sha256_from_s3_checksum("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=-3"),
+            None
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::archive;
+use crate::errors::BackupResult;
+use crate::options::Options as CLIopts;
+use crate::s3::{RemoteObject, S3Client};
+
+use log::{error, info};
+
+#[derive(Debug, Default)]
+pub struct MigrateSummary {
+    /// Objects copied server-side
+    pub copied: usize,
+    /// Objects downloaded from the source and uploaded to the destination
+    pub transferred: usize,
+    /// Archived objects which are still being retrieved and weren't migrated yet
+    pub pending: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl MigrateSummary {
+    pub fn succeeded(&self) -> bool {
+        self.failed.is_empty() && self.pending.is_empty()
+    }
+}
+
+/// Copies every object into the destination bucket with its storage class. Server-side copies only
+/// work when both buckets are reachable from the destination's region, so any other copy falls
+/// back to streaming the object through this machine.
+pub async fn migrate_objects(
+    source: &S3Client,
+    destination: &S3Client,
+    objects: Vec<RemoteObject>,
+    server_side: bool,
+    args: &CLIopts,
+) -> MigrateSummary {
+    let mut summary = MigrateSummary::default();
+
+    let (pending, failed) = archive::retrieve_all(source, &objects, args).await;
+    summary.pending = pending;
+    summary.failed = failed;
+
+    let keys = objects
+        .into_iter()
+        .map(|object| object.key)
+        .filter(|key| !summary.pending.contains(key) && !summary.failed.contains(key))
+        .collect::<Vec<_>>();
+
+    for key in keys {
+        if server_side {
+            match destination.copy_from(source.bucket(), &key).await {
+                Ok(_) => {
+                    info!("Copied {}", key);
+                    summary.copied += 1;
+                    continue;
+                }
+                Err(err) => info!(
+                    "Unable to copy {} server-side, transferring it instead: {}",
+                    key, err
+                ),
+            }
+        }
+
+        match transfer_object(source, destination, &key).await {
+            Ok(()) => {
+                info!("Transferred {}", key);
+                summary.transferred += 1;
+            }
+            Err(err) => {
+                error!("Failed to migrate {}: {}", key, err);
+                summary.failed.push(key);
+            }
+        }
+    }
+
+    summary
+}
+
+async fn transfer_object(source: &S3Client, destination: &S3Client, key: &str) -> BackupResult<()> {
+    let object = source.download_file(key).await?;
+    let metadata = object.metadata().cloned();
+    let content_length = object.content_length();
+
+    destination
+        .upload_stream(object.body, key, content_length, metadata)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migration_with_pending_retrievals_isnt_done() {
+        assert!(MigrateSummary::default().succeeded());
+
+        let pending = MigrateSummary {
+            pending: vec!["archived".to_owned()],
+            ..MigrateSummary::default()
+        };
+        assert!(!pending.succeeded());
+
+        let failed = MigrateSummary {
+            failed: vec!["broken".to_owned()],
+            ..MigrateSummary::default()
+        };
+        assert!(!failed.succeeded());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::errors::BackupResult;
+use crate::s3::S3Client;
+use crate::summary::format_bytes;
+
+use log::{info, warn};
+
+/// A multipart upload which was started but never completed or aborted, e.g. by an interrupted run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingUpload {
+    pub key: String,
+    pub upload_id: String,
+    /// Seconds since the epoch at which the upload was started
+    pub initiated: Option<i64>,
+    /// The combined size of the parts uploaded so far, which are billed until the upload is aborted
+    pub bytes: i64,
+}
+
+pub async fn find_dangling(client: &S3Client) -> BackupResult<Vec<DanglingUpload>> {
+    let mut uploads = Vec::new();
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+
+    loop {
+        let response = client
+            .list_multipart_uploads(key_marker, upload_id_marker)
+            .await?;
+
+        for upload in response.uploads().unwrap_or_default() {
+            let (key, upload_id) = match (upload.key(), upload.upload_id()) {
+                (Some(key), Some(upload_id)) => (key, upload_id),
+                _ => continue,
+            };
+
+            uploads.push(DanglingUpload {
+                key: key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                initiated: upload.initiated().map(|date| date.secs()),
+                bytes: uploaded_bytes(client, key, upload_id).await?,
+            });
+        }
+
+        if !response.is_truncated() {
+            break;
+        }
+        key_marker = response.next_key_marker().map(str::to_owned);
+        upload_id_marker = response.next_upload_id_marker().map(str::to_owned);
+    }
+
+    Ok(uploads)
+}
+
+async fn uploaded_bytes(client: &S3Client, key: &str, upload_id: &str) -> BackupResult<i64> {
+    let mut bytes = 0;
+    let mut part_number_marker = None;
+
+    loop {
+        let response = client
+            .list_parts(key, upload_id, part_number_marker)
+            .await?;
+        bytes += response
+            .parts()
+            .unwrap_or_default()
+            .iter()
+            .map(|part| part.size())
+            .sum::<i64>();
+
+        if !response.is_truncated() {
+            return Ok(bytes);
+        }
+        part_number_marker = response.next_part_number_marker().map(str::to_owned);
+    }
+}
+
+/// The age in whole days, or `None` when the provider didn't report when the upload started
+pub fn age_in_days(upload: &DanglingUpload, now: i64) -> Option<i64> {
+    upload
+        .initiated
+        .map(|initiated| (now - initiated).max(0) / (24 * 60 * 60))
+}
+
+pub fn report(bucket: &str, uploads: &[DanglingUpload], now: i64) {
+    if uploads.is_empty() {
+        info!("No incomplete multipart uploads in {}", bucket);
+        return;
+    }
+
+    let total: i64 = uploads.iter().map(|upload| upload.bytes).sum();
+    warn!(
+        "Found {} incomplete multipart uploads in {} holding {}:",
+        uploads.len(),
+        bucket,
+        format_bytes(total as f64)
+    );
+    for upload in uploads {
+        let age = age_in_days(upload, now).map_or_else(
+            || "unknown age".to_owned(),
+            |days| format!("{} days old", days),
+        );
+        warn!(
+            "  {} ({}): {}, {}",
+            upload.key,
+            upload.upload_id,
+            age,
+            format_bytes(upload.bytes as f64)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(initiated: Option<i64>) -> DanglingUpload {
+        DanglingUpload {
+            key: "key".to_owned(),
+            upload_id: "id".to_owned(),
+            initiated,
+            bytes: 0,
+        }
+    }
+
+    #[test]
+    fn age_is_counted_in_whole_days() {
+        let day = 24 * 60 * 60;
+        assert_eq!(age_in_days(&upload(Some(0)), 3 * day - 1), Some(2));
+        assert_eq!(age_in_days(&upload(Some(day)), 0), Some(0));
+        assert_eq!(age_in_days(&upload(None), day), None);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::summary::Summary;
+
+use serde::Serialize;
+
+/// The JSON body posted to the webhook once a bucket has been backed up
+#[derive(Debug, Serialize)]
+pub struct Notification<'a> {
+    pub bucket: &'a str,
+    pub succeeded: bool,
+    pub candidates: usize,
+    pub uploaded: usize,
+    pub skipped: usize,
+    pub metadata_updated: usize,
+    pub copied: usize,
+    pub bytes_uploaded: u64,
+    pub failed: &'a [String],
+}
+
+impl<'a> Notification<'a> {
+    pub fn new(bucket: &'a str, succeeded: bool, summary: &'a Summary) -> Notification<'a> {
+        Notification {
+            bucket,
+            succeeded,
+            candidates: summary.candidates,
+            uploaded: summary.uploaded,
+            skipped: summary.skipped,
+            metadata_updated: summary.metadata_updated,
+            copied: summary.copied,
+            bytes_uploaded: summary.bytes_uploaded,
+            failed: &summary.failed,
+        }
+    }
+}
+
+pub async fn send(url: &str, notification: &Notification<'_>) -> reqwest::Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .json(notification)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notification_carries_the_summary() {
+        let summary = Summary {
+            candidates: 3,
+            uploaded: 1,
+            skipped: 1,
+            failed: vec!["a.txt".to_owned()],
+            bytes_uploaded: 42,
+            ..Summary::default()
+        };
+
+        let body = serde_json::to_value(Notification::new("bucket", false, &summary)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "bucket": "bucket",
+                "succeeded": false,
+                "candidates": 3,
+                "uploaded": 1,
+                "skipped": 1,
+                "metadata_updated": 0,
+                "copied": 0,
+                "bytes_uploaded": 42,
+                "failed": ["a.txt"],
+            })
+        );
+    }
+}
@@ -1,8 +1,219 @@
+use crate::key_template::KeyTemplate;
+
+use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
+/// What to do with a source file or directory that can't be read due to its permissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionDeniedPolicy {
+    Warn,
+    Skip,
+    Fail,
+}
+
+impl FromStr for PermissionDeniedPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "warn" => Ok(PermissionDeniedPolicy::Warn),
+            "skip" => Ok(PermissionDeniedPolicy::Skip),
+            "fail" => Ok(PermissionDeniedPolicy::Fail),
+            _ => Err(format!("Unknown permission denied policy: {}", value)),
+        }
+    }
+}
+
+/// What to do with a file whose name would produce a problematic object key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblematicKeyPolicy {
+    Sanitize,
+    Skip,
+    Keep,
+}
+
+impl FromStr for ProblematicKeyPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sanitize" => Ok(ProblematicKeyPolicy::Sanitize),
+            "skip" => Ok(ProblematicKeyPolicy::Skip),
+            "keep" => Ok(ProblematicKeyPolicy::Keep),
+            _ => Err(format!("Unknown problematic key policy: {}", value)),
+        }
+    }
+}
+
+/// What to do with a restored file which doesn't match the metadata stored on upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMismatchAction {
+    Fail,
+    Redownload,
+    Quarantine,
+}
+
+impl FromStr for VerifyMismatchAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fail" => Ok(VerifyMismatchAction::Fail),
+            "redownload" => Ok(VerifyMismatchAction::Redownload),
+            "quarantine" => Ok(VerifyMismatchAction::Quarantine),
+            _ => Err(format!("Unknown verify mismatch action: {}", value)),
+        }
+    }
+}
+
+/// Whether a restore replaces a local file which already exists at the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExisting {
+    Skip,
+    Overwrite,
+    OverwriteIfNewer,
+    OverwriteIfDifferentSize,
+}
+
+impl FromStr for OnExisting {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(OnExisting::Skip),
+            "overwrite" => Ok(OnExisting::Overwrite),
+            "overwrite-if-newer" => Ok(OnExisting::OverwriteIfNewer),
+            "overwrite-if-different-size" => Ok(OnExisting::OverwriteIfDifferentSize),
+            _ => Err(format!("Unknown existing file policy: {}", value)),
+        }
+    }
+}
+
+/// What to do when the local clock disagrees with the server's by more than the allowed skew
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSkewPolicy {
+    Warn,
+    Abort,
+}
+
+impl FromStr for ClockSkewPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "warn" => Ok(ClockSkewPolicy::Warn),
+            "abort" => Ok(ClockSkewPolicy::Abort),
+            _ => Err(format!("Unknown clock skew policy: {}", value)),
+        }
+    }
+}
+
+/// Which outcomes of a run are reported to the webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyOn {
+    Success,
+    Failure,
+    Always,
+}
+
+impl NotifyOn {
+    pub fn applies_to(self, succeeded: bool) -> bool {
+        match self {
+            NotifyOn::Success => succeeded,
+            NotifyOn::Failure => !succeeded,
+            NotifyOn::Always => true,
+        }
+    }
+}
+
+impl FromStr for NotifyOn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "success" => Ok(NotifyOn::Success),
+            "failure" => Ok(NotifyOn::Failure),
+            "always" => Ok(NotifyOn::Always),
+            _ => Err(format!("Unknown notification condition: {}", value)),
+        }
+    }
+}
+
+/// The order in which files claim the budget of a dry run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPriority {
+    Newest,
+    Oldest,
+    Smallest,
+    Largest,
+}
+
+impl FromStr for BudgetPriority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "newest" => Ok(BudgetPriority::Newest),
+            "oldest" => Ok(BudgetPriority::Oldest),
+            "smallest" => Ok(BudgetPriority::Smallest),
+            "largest" => Ok(BudgetPriority::Largest),
+            _ => Err(format!("Unknown budget priority: {}", value)),
+        }
+    }
+}
+
+/// Selects objects whose stored metadata has the given value for the key, parsed from `key=value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataFilter {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for MetadataFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(MetadataFilter {
+                key: key.to_lowercase(),
+                value: value.to_owned(),
+            }),
+            _ => Err(format!(
+                "Expected a metadata filter as key=value: {}",
+                value
+            )),
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional binary suffix, e.g. `512`, `64K`, `10M` or `2G`
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier: u64 = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("Unknown size suffix: {}", suffix)),
+            };
+            (&value[..index], multiplier)
+        }
+        _ => (value, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: {}", value))
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Options {
-    /// Directory to backup
+    /// Directory to backup, or to restore into when using --restore
     #[structopt(parse(from_os_str))]
     pub path: std::path::PathBuf,
 
@@ -19,8 +230,8 @@ pub struct Options {
     pub bucket: String,
 
     /// Bucket to store data in
-    #[structopt(long)]
-    pub bucket_backup: String,
+    #[structopt(long, required_unless_one = &["restore", "migrate-to", "diff-with"])]
+    pub bucket_backup: Option<String>,
 
     /// The storage class for the individual files
     /// Accepted values:
@@ -46,4 +257,512 @@ pub struct Options {
     /// ```
     #[structopt(default_value = "AES256", short, long)]
     pub encryption: String,
+
+    /// Directory used to cache listing progress so an interrupted listing can resume where it left off
+    #[structopt(long, parse(from_os_str))]
+    pub resume_listing: Option<std::path::PathBuf>,
+
+    /// Restore the contents of the bucket into the directory instead of backing it up
+    #[structopt(long)]
+    pub restore: bool,
+
+    /// Verify restored files against the size, hash and mode recorded when they were uploaded
+    #[structopt(long, requires = "restore")]
+    pub verify: bool,
+
+    /// What to do when a restored file fails verification. An existing local file is only replaced by
+    /// a download which passed it. Quarantined downloads get a `.corrupt` extension and don't fail
+    /// the restore
+    /// Accepted values:
+    /// ```
+    ///  fail
+    ///  redownload
+    ///  quarantine
+    /// ```
+    #[structopt(
+        default_value = "fail",
+        long,
+        possible_values = &["fail", "redownload", "quarantine"]
+    )]
+    pub on_verify_mismatch: VerifyMismatchAction,
+
+    /// The retrieval tier used for objects in GLACIER or DEEP_ARCHIVE
+    /// Accepted values:
+    /// ```
+    ///  Bulk
+    ///  Standard
+    ///  Expedited
+    /// ```
+    #[structopt(
+        default_value = "Bulk",
+        long,
+        possible_values = &["Bulk", "Standard", "Expedited"]
+    )]
+    pub restore_tier: String,
+
+    /// How many days retrieved copies of archived objects remain available
+    #[structopt(default_value = "7", long)]
+    pub restore_days: i32,
+
+    /// Wait for archived objects to be retrieved instead of asking to run the restore again later
+    #[structopt(long, requires = "restore")]
+    pub wait_for_archive: bool,
+
+    /// Configure the Intelligent-Tiering archive access tiers of both buckets instead of backing up
+    #[structopt(long, conflicts_with = "restore")]
+    pub configure_tiering: bool,
+
+    /// Days without access before objects move to the Archive Access tier
+    #[structopt(default_value = "90", long)]
+    pub archive_access_days: i32,
+
+    /// Days without access before objects move to the Deep Archive Access tier
+    #[structopt(long)]
+    pub deep_archive_access_days: Option<i32>,
+
+    /// Report source files which are writable by others or setuid/setgid (unix only)
+    #[structopt(long)]
+    pub flag_insecure_perms: bool,
+
+    /// List the bucket's top-level prefixes concurrently, using at most this many parallel listings
+    #[structopt(long, conflicts_with = "resume-listing")]
+    pub parallel_list_prefixes: Option<usize>,
+
+    /// How to handle source files which can't be read due to their permissions
+    /// Accepted values:
+    /// ```
+    ///  warn
+    ///  skip
+    ///  fail
+    /// ```
+    #[structopt(default_value = "warn", long, possible_values = &["warn", "skip", "fail"])]
+    pub on_permission_denied: PermissionDeniedPolicy,
+
+    /// Write the objects of both buckets to this file in the S3 Inventory CSV format
+    #[structopt(long, parse(from_os_str))]
+    pub inventory_csv: Option<std::path::PathBuf>,
+
+    /// Don't back up while the machine is running on battery
+    #[structopt(long)]
+    pub skip_on_battery: bool,
+
+    /// Pause for --battery-pause after every file which is hashed or uploaded, and limit listing to
+    /// a single request at a time, while the machine is running on battery
+    #[structopt(long)]
+    pub throttle_on_battery: bool,
+
+    /// How many milliseconds --throttle-on-battery pauses after every file
+    #[structopt(default_value = "500", long)]
+    pub battery_pause: u64,
+
+    /// Re-upload changed files, hashing those of at least this size (e.g. 10M) and comparing the
+    /// size and modification time of smaller ones
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub checksum_over: Option<u64>,
+
+    /// Include the average and peak upload throughput in the summary
+    #[structopt(long)]
+    pub report_throughput: bool,
+
+    /// Fail when there are no files to back up, which usually means the source isn't mounted
+    #[structopt(long)]
+    pub fail_on_empty: bool,
+
+    /// Reuse a completed listing from the --resume-listing directory for this many seconds
+    #[structopt(long, requires = "resume-listing")]
+    pub listing_cache_ttl: Option<u64>,
+
+    /// Include the number of files and bytes uploaded per top-level directory in the summary
+    #[structopt(long)]
+    pub report_per_directory: bool,
+
+    /// Update the stored metadata of unchanged files whose size or mode no longer matches, without
+    /// uploading their content again. Objects in GLACIER or DEEP_ARCHIVE are skipped since they
+    /// can't be copied
+    #[structopt(long)]
+    pub update_metadata: bool,
+
+    /// How to handle file names with trailing whitespace or control characters. Sanitized keys
+    /// record the original path in the object's metadata
+    /// Accepted values:
+    /// ```
+    ///  sanitize
+    ///  skip
+    ///  keep
+    /// ```
+    #[structopt(default_value = "keep", long, possible_values = &["sanitize", "skip", "keep"])]
+    pub on_problematic_key: ProblematicKeyPolicy,
+
+    /// Directory in which to record the files backed up so far, so an interrupted run can --resume
+    #[structopt(long, parse(from_os_str))]
+    pub checkpoint: Option<std::path::PathBuf>,
+
+    /// Skip the files recorded in the checkpoint of an interrupted run without listing the bucket
+    #[structopt(long, requires = "checkpoint")]
+    pub resume: bool,
+
+    /// Copy files whose content was already uploaded during this run instead of uploading them again.
+    /// Not available with the GLACIER and DEEP_ARCHIVE storage classes, whose objects can't be copied.
+    #[structopt(long)]
+    pub dedupe_within_run: bool,
+
+    /// Show what would be uploaded to each bucket and ask for confirmation before uploading
+    #[structopt(long)]
+    pub interactive: bool,
+
+    /// Proceed without asking for confirmation in --interactive mode
+    #[structopt(long, short = "y")]
+    pub yes: bool,
+
+    /// Don't upload files which are empty
+    #[structopt(long)]
+    pub skip_empty_files: bool,
+
+    /// Treat a listing which the provider rejects as a missing bucket as an empty bucket, for
+    /// S3-compatible providers which do so for brand-new buckets
+    #[structopt(long)]
+    pub treat_list_error_as_empty: bool,
+
+    /// How many times a single file's upload is retried after throttling, server errors, timeouts or
+    /// connection failures before it's marked as failed. Other errors fail the file right away.
+    #[structopt(default_value = "2", long)]
+    pub retries_per_file: u32,
+
+    /// How many upload retries are allowed across all files of a bucket
+    #[structopt(default_value = "50", long)]
+    pub retry_budget: u32,
+
+    /// Write the SHA-256 of every uploaded file to this file, in the format read by `sha256sum -c`
+    #[structopt(long, parse(from_os_str))]
+    pub checksums_file: Option<std::path::PathBuf>,
+
+    /// Remove this many leading directories from every key, like tar's --strip-components
+    #[structopt(default_value = "0", long)]
+    pub strip_components: usize,
+
+    /// How to handle a local clock which is off by more than --max-clock-skew, since change
+    /// detection relies on modification times
+    /// Accepted values:
+    /// ```
+    ///  warn
+    ///  abort
+    /// ```
+    #[structopt(default_value = "warn", long, possible_values = &["warn", "abort"])]
+    pub on_clock_skew: ClockSkewPolicy,
+
+    /// How many seconds the local clock may differ from the server's clock
+    #[structopt(default_value = "300", long)]
+    pub max_clock_skew: u64,
+
+    /// Write the paths of files which failed to back up to this file, separated by NUL bytes
+    /// like `find -print0`, or to stderr when given `-`
+    #[structopt(long, parse(from_os_str))]
+    pub failed_files_out: Option<std::path::PathBuf>,
+
+    /// Skip files and directories whose path relative to the root matches this glob, e.g. `**/*.tmp`
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Read exclude globs from this file, one per line, ignoring blank lines and `#` comments
+    #[structopt(long, parse(from_os_str))]
+    pub exclude_from: Option<std::path::PathBuf>,
+
+    /// Store each file's creation time in the object's metadata, on platforms which record it
+    #[structopt(long)]
+    pub preserve_birthtime: bool,
+
+    /// Only back up files with one of these comma-separated extensions, e.g. `jpg,png,raw`
+    #[structopt(long, use_delimiter = true)]
+    pub only_extensions: Vec<String>,
+
+    /// Don't back up files with one of these comma-separated extensions, e.g. `tmp,log`
+    #[structopt(long, use_delimiter = true)]
+    pub skip_extensions: Vec<String>,
+
+    /// Report how many uploaded files were modified within the last day, week, month and year
+    #[structopt(long)]
+    pub report_age_distribution: bool,
+
+    /// Report incomplete multipart uploads left in the bucket, with their age and size, before uploading
+    #[structopt(long)]
+    pub report_dangling_multipart: bool,
+
+    /// Limit how many requests are sent per second across all S3 calls, for providers which
+    /// rate-limit by request count
+    #[structopt(long)]
+    pub max_requests_per_second: Option<u32>,
+
+    /// Run this shell command before uploading, e.g. to create a filesystem snapshot to back up
+    /// from, and abort the run if it fails
+    #[structopt(long)]
+    pub pre_command: Option<String>,
+
+    /// Run this shell command once uploading finishes, even if the pre-command failed, e.g. to
+    /// remove the snapshot again
+    #[structopt(long)]
+    pub post_command: Option<String>,
+
+    /// Check whether the bucket treats keys case-insensitively by uploading two probes, and if so
+    /// give files whose keys only differ in case a `~n` suffix instead of overwriting each other
+    #[structopt(long)]
+    pub detect_case_folding: bool,
+
+    /// Abort the run once the temporary directory's filesystem has less than this much free
+    /// space (e.g. 5G), checked before every file is processed
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub min_free_space: Option<u64>,
+
+    /// Add the size, mode and hash metadata to unchanged objects which were uploaded without it,
+    /// using a metadata-only copy. Objects in GLACIER or DEEP_ARCHIVE are skipped since they can't
+    /// be copied
+    #[structopt(long)]
+    pub force_refresh_metadata: bool,
+
+    /// Log the progress and flush the checkpoint whenever the process receives SIGUSR1 (unix only)
+    #[structopt(long)]
+    pub checkpoint_on_signal: bool,
+
+    /// Describe what would be uploaded without uploading anything
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// During a --dry-run, confirm write access by uploading and removing an empty probe object in
+    /// every top-level directory that would receive uploads
+    #[structopt(long, requires = "dry-run")]
+    pub check_permissions: bool,
+
+    /// Only back up the files git tracks under the root, including those in submodules
+    #[structopt(long)]
+    pub from_git: bool,
+
+    /// With --from-git, also back up untracked files which aren't ignored
+    #[structopt(long, requires = "from-git")]
+    pub git_untracked: bool,
+
+    /// Copy every object in the bucket to this bucket instead of backing up, e.g. to move
+    /// DEEP_ARCHIVE objects to another storage class
+    #[structopt(long, conflicts_with = "restore")]
+    pub migrate_to: Option<String>,
+
+    /// The region of the --migrate-to bucket, which defaults to the source region. Server-side
+    /// copies are only used within a region.
+    #[structopt(long, requires = "migrate-to")]
+    pub migrate_region: Option<String>,
+
+    /// The storage class of migrated objects
+    #[structopt(default_value = "STANDARD", long)]
+    pub migrate_class: String,
+
+    /// Only log the final summary and errors, e.g. for cron jobs
+    #[structopt(long)]
+    pub summary_only: bool,
+
+    /// Only restore objects whose stored metadata has this value, e.g. `mode=644`. Can be given
+    /// several times, in which case every filter has to match.
+    #[structopt(long, number_of_values = 1, requires = "restore")]
+    pub filter_metadata: Vec<MetadataFilter>,
+
+    /// Flush every restored file and its directory to disk before moving on, so a power loss can't
+    /// leave truncated files behind
+    #[structopt(long, requires = "restore")]
+    pub durable_restore: bool,
+
+    /// Abort before uploading anything when more than this many new files would be uploaded, or
+    /// ask for confirmation in --interactive mode
+    #[structopt(long)]
+    pub max_new_files: Option<usize>,
+
+    /// Hash every file and report how much --dedupe-within-run would save, without uploading
+    #[structopt(long)]
+    pub dedupe_report: bool,
+
+    /// Refuse to back up unless this file exists in the root, e.g. `.backup-root`, to catch a
+    /// network share which didn't mount
+    #[structopt(long, parse(from_os_str))]
+    pub require_marker: Option<std::path::PathBuf>,
+
+    /// Compare the bucket's objects with this bucket instead of backing up, listing the keys only in
+    /// either bucket and those whose size or ETag differs
+    #[structopt(long, conflicts_with_all = &["restore", "migrate-to"])]
+    pub diff_with: Option<String>,
+
+    /// The region of the --diff-with bucket, which defaults to the bucket's region
+    #[structopt(long, requires = "diff-with")]
+    pub diff_region: Option<String>,
+
+    /// Remove this prefix from every key when restoring, e.g. `backups/laptop`, skipping objects
+    /// whose key doesn't start with it
+    #[structopt(long, requires = "restore")]
+    pub strip_prefix: Option<String>,
+
+    /// Rebuild the S3 client's connections after this many consecutive uploads failed to connect,
+    /// or never when 0
+    #[structopt(default_value = "3", long)]
+    pub reconnect_after: u32,
+
+    /// POST a JSON summary to this URL once each bucket has been backed up. A failing webhook is
+    /// logged but doesn't fail the run.
+    #[structopt(long)]
+    pub notify_webhook: Option<String>,
+
+    /// When to call the webhook
+    /// Accepted values:
+    /// ```
+    ///  success
+    ///  failure
+    ///  always
+    /// ```
+    #[structopt(default_value = "always", long, possible_values = &["success", "failure", "always"])]
+    pub notify_on: NotifyOn,
+
+    /// Re-upload files whose content doesn't match the ETag of their object, including multipart
+    /// ETags. Objects encrypted with aws:kms don't have content-based ETags, so their ETags aren't
+    /// compared.
+    #[structopt(long)]
+    pub compare_etag: bool,
+
+    /// The part size of multipart uploads made by other tools (e.g. 8M), tried first when comparing
+    /// multipart ETags
+    #[structopt(long, requires = "compare-etag", parse(try_from_str = parse_size))]
+    pub etag_part_size: Option<u64>,
+
+    /// Walk every real directory once, even when several symlinks lead to it, so its files aren't
+    /// uploaded under several keys
+    #[structopt(long)]
+    pub walk_symlinked_dirs_once: bool,
+
+    /// Write every skipped file and directory with the reason it was skipped to this file, as one
+    /// JSON object per line
+    #[structopt(long, parse(from_os_str))]
+    pub skipped_report: Option<std::path::PathBuf>,
+
+    /// Store every file below this prefix, e.g. `backups/laptop`
+    #[structopt(long)]
+    pub prefix: Option<String>,
+
+    /// Build keys from this template instead, e.g. `{prefix}/{host}/{date}/{relpath}`, where
+    /// `{relpath}` is the file's path relative to the root and `{date}` is today's UTC date
+    #[structopt(long)]
+    pub key_template: Option<KeyTemplate>,
+
+    /// Check the credentials before starting and warn if they expire within this many minutes,
+    /// the expected duration of the run
+    #[structopt(long)]
+    pub verify_credentials_expiry: Option<u64>,
+
+    /// Download every uploaded file again in ranges and compare it against the local file. Not
+    /// available with the GLACIER and DEEP_ARCHIVE storage classes, whose objects can't be downloaded.
+    #[structopt(long)]
+    pub verify_after_upload: bool,
+
+    /// Size of the ranges compared by --verify-after-upload (e.g. 8M), which bounds the memory used
+    #[structopt(long, default_value = "8M", parse(try_from_str = parse_size))]
+    pub verify_chunk_size: u64,
+
+    /// Summarise how many objects and bytes the bucket holds in each storage class
+    #[structopt(long)]
+    pub report_storage_class_distribution: bool,
+
+    /// With --dry-run, report which of the files to upload fit within this many bytes (e.g. 100G)
+    /// when taken in the order of --budget-priority, and which don't
+    #[structopt(long, requires = "dry-run", parse(try_from_str = parse_size))]
+    pub budget: Option<u64>,
+
+    /// Which files claim the --budget first
+    /// Accepted values:
+    /// ```
+    ///  newest
+    ///  oldest
+    ///  smallest
+    ///  largest
+    /// ```
+    #[structopt(
+        default_value = "newest",
+        long,
+        possible_values = &["newest", "oldest", "smallest", "largest"]
+    )]
+    pub budget_priority: BudgetPriority,
+
+    /// What a restore does with local files which already exist. overwrite-if-newer only replaces
+    /// files which weren't modified since the object was uploaded.
+    /// Accepted values:
+    /// ```
+    ///  skip
+    ///  overwrite
+    ///  overwrite-if-newer
+    ///  overwrite-if-different-size
+    /// ```
+    #[structopt(
+        default_value = "overwrite",
+        long,
+        possible_values = &["skip", "overwrite", "overwrite-if-newer", "overwrite-if-different-size"]
+    )]
+    pub on_existing: OnExisting,
+
+    /// Keep this file updated with the progress of the upload as JSON, for monitoring runs without
+    /// a terminal
+    #[structopt(long, parse(from_os_str))]
+    pub progress_file: Option<PathBuf>,
+
+    /// Key of an index of content hashes shared by every host backing up to the bucket, e.g. with
+    /// their own --prefix. Files whose content is already stored under another key are copied from
+    /// it instead of uploaded, like --dedupe-within-run, and so is not available with the GLACIER and
+    /// DEEP_ARCHIVE storage classes either. The index itself is stored as STANDARD and left out of
+    /// restores, diffs and migrations.
+    #[structopt(long)]
+    pub dedupe_index: Option<String>,
+
+    /// Send the SHA-256 of every upload for S3 to validate and store with the object, which
+    /// restores with --verify compare against
+    #[structopt(long)]
+    pub checksum_sha256: bool,
+
+    /// Suggest a lifecycle rule for buckets which don't abort incomplete multipart uploads
+    #[structopt(long)]
+    pub check_lifecycle: bool,
+
+    /// Add the lifecycle rules suggested by --check-lifecycle to the buckets
+    #[structopt(long)]
+    pub apply_lifecycle_suggestions: bool,
+
+    /// Also treat file names with characters outside of ASCII letters, digits and `!-_.*'()`, which
+    /// AWS lists as safe in keys, as problematic, for providers which reject other characters.
+    /// They're handled according to --on-problematic-key, which is sanitize unless set to skip, and
+    /// sanitized names which collide get a ~n suffix.
+    #[structopt(long)]
+    pub safe_key_charset: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permission_denied_policies_parse() {
+        assert_eq!("warn".parse(), Ok(PermissionDeniedPolicy::Warn));
+        assert_eq!("skip".parse(), Ok(PermissionDeniedPolicy::Skip));
+        assert_eq!("fail".parse(), Ok(PermissionDeniedPolicy::Fail));
+        assert!("ignore".parse::<PermissionDeniedPolicy>().is_err());
+    }
+
+    #[test]
+    fn verify_mismatch_actions_parse() {
+        assert_eq!("fail".parse(), Ok(VerifyMismatchAction::Fail));
+        assert_eq!("redownload".parse(), Ok(VerifyMismatchAction::Redownload));
+        assert_eq!("quarantine".parse(), Ok(VerifyMismatchAction::Quarantine));
+        assert!("delete".parse::<VerifyMismatchAction>().is_err());
+    }
+
+    #[test]
+    fn metadata_filters_need_a_key() {
+        assert_eq!(
+            "Source-Host=a=b".parse(),
+            Ok(MetadataFilter {
+                key: "source-host".to_owned(),
+                value: "a=b".to_owned()
+            })
+        );
+        assert!("=laptop".parse::<MetadataFilter>().is_err());
+        assert!("laptop".parse::<MetadataFilter>().is_err());
+    }
 }
@@ -0,0 +1,53 @@
+use crate::s3::S3Client;
+
+use aws_sdk_s3::types::ByteStream;
+use log::warn;
+use std::collections::{BTreeSet, HashMap};
+
+const PERMISSION_PROBE_NAME: &str = ".backup-rs-permission-probe";
+
+/// Uploads and removes an empty probe object in every prefix which would receive uploads, returning
+/// the prefixes where the upload was refused along with the reason
+pub async fn probe_prefixes(
+    client: &S3Client,
+    prefixes: &BTreeSet<String>,
+) -> Vec<(String, String)> {
+    let mut denied = Vec::new();
+
+    for prefix in prefixes {
+        let key = probe_key(prefix);
+
+        match client
+            .upload_file(ByteStream::from_static(b""), &key, HashMap::new())
+            .await
+        {
+            Ok(_) => {
+                if let Err(err) = client.delete_file(&key).await {
+                    warn!("Unable to remove the permission probe {}: {}", key, err);
+                }
+            }
+            Err(err) => denied.push((prefix.to_owned(), err.to_string())),
+        }
+    }
+
+    denied
+}
+
+/// The probe of the root, which is grouped as `.`, goes at the top of the bucket
+fn probe_key(prefix: &str) -> String {
+    match prefix {
+        "." => PERMISSION_PROBE_NAME.to_owned(),
+        prefix => format!("{}/{}", prefix, PERMISSION_PROBE_NAME),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_goes_in_the_prefix_it_checks() {
+        assert_eq!(probe_key("."), ".backup-rs-permission-probe");
+        assert_eq!(probe_key("photos"), "photos/.backup-rs-permission-probe");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::options::BudgetPriority;
+use crate::summary::format_bytes;
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::time::SystemTime;
+
+/// A file the upload would send, only kept when planning against a budget
+#[derive(Debug, PartialEq, Eq)]
+pub struct PlannedFile {
+    pub key: String,
+    pub bytes: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// What an upload would do, computed up front so it can be confirmed before anything is sent
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub new_files: usize,
+    pub changed_files: usize,
+    pub unchanged_files: usize,
+    pub bytes: u64,
+    /// The top-level directories which would receive uploads, with files in the root as `.`
+    pub prefixes: BTreeSet<String>,
+    pub files: Vec<PlannedFile>,
+}
+
+impl Plan {
+    pub fn record_prefix(&mut self, filename_segments: &[String]) {
+        let prefix = match filename_segments {
+            [directory, _, ..] => directory.as_str(),
+            _ => ".",
+        };
+        self.prefixes.insert(prefix.to_owned());
+    }
+
+    /// Takes the files in priority order, passing over any which no longer fit so smaller ones
+    /// further down can still use the remaining budget
+    pub fn fit_budget(
+        &mut self,
+        budget: u64,
+        priority: BudgetPriority,
+    ) -> (Vec<&PlannedFile>, Vec<&PlannedFile>) {
+        match priority {
+            BudgetPriority::Newest => self.files.sort_by_key(|f| Reverse(f.modified)),
+            BudgetPriority::Oldest => self.files.sort_by_key(|f| f.modified),
+            BudgetPriority::Smallest => self.files.sort_by_key(|f| f.bytes),
+            BudgetPriority::Largest => self.files.sort_by_key(|f| Reverse(f.bytes)),
+        }
+
+        let mut remaining = budget;
+        self.files.iter().partition(|file| {
+            let fits = file.bytes <= remaining;
+            if fits {
+                remaining -= file.bytes;
+            }
+            fits
+        })
+    }
+
+    pub fn describe_budget(&mut self, budget: u64, priority: BudgetPriority) -> String {
+        let (fits, excluded) = self.fit_budget(budget, priority);
+        let fitting_bytes: u64 = fits.iter().map(|f| f.bytes).sum();
+
+        let mut description = format!(
+            "{} files ({}) fit within the budget of {}:",
+            fits.len(),
+            format_bytes(fitting_bytes as f64),
+            format_bytes(budget as f64)
+        );
+        for file in &fits {
+            description.push_str(&format!(
+                "\n  {} ({})",
+                file.key,
+                format_bytes(file.bytes as f64)
+            ));
+        }
+
+        description.push_str(&format!("\n{} files don't fit:", excluded.len()));
+        for file in &excluded {
+            description.push_str(&format!(
+                "\n  {} ({})",
+                file.key,
+                format_bytes(file.bytes as f64)
+            ));
+        }
+        description
+    }
+
+    pub fn describe(&self, bucket: &str) -> String {
+        format!(
+            "Uploading to {} would add {} new files and update {} changed files ({}), leaving {} unchanged",
+            bucket,
+            self.new_files,
+            self.changed_files,
+            format_bytes(self.bytes as f64),
+            self.unchanged_files
+        )
+    }
+}
+
+/// Asks for confirmation on stdout, treating anything other than an explicit yes as a refusal
+pub fn confirm(input: &mut impl BufRead) -> io::Result<bool> {
+    print!("Proceed? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn only_an_explicit_yes_confirms() {
+        for (answer, confirmed) in [
+            ("y\n", true),
+            ("YES\n", true),
+            ("n\n", false),
+            ("\n", false),
+            ("", false),
+        ] {
+            assert_eq!(
+                confirm(&mut answer.as_bytes()).unwrap(),
+                confirmed,
+                "{:?}",
+                answer
+            );
+        }
+    }
+
+    #[test]
+    fn plan_describes_what_the_upload_would_do() {
+        let mut plan = Plan {
+            new_files: 2,
+            changed_files: 1,
+            unchanged_files: 7,
+            bytes: 2048,
+            ..Plan::default()
+        };
+        plan.record_prefix(&["photos".to_owned(), "a.jpg".to_owned()]);
+        plan.record_prefix(&["notes.txt".to_owned()]);
+
+        assert_eq!(
+            plan.describe("bucket"),
+            "Uploading to bucket would add 2 new files and update 1 changed files (2.0 KiB), leaving 7 unchanged"
+        );
+        assert_eq!(
+            plan.prefixes.into_iter().collect::<Vec<_>>(),
+            [".", "photos"]
+        );
+    }
+
+    fn planned_file(key: &str, bytes: u64, modified: u64) -> PlannedFile {
+        PlannedFile {
+            key: key.to_owned(),
+            bytes,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(modified)),
+        }
+    }
+
+    fn keys(files: &[&PlannedFile]) -> Vec<String> {
+        files.iter().map(|file| file.key.clone()).collect()
+    }
+
+    #[test]
+    fn budget_is_filled_in_order_of_priority() {
+        let mut plan = Plan {
+            files: vec![
+                planned_file("old-small", 10, 1),
+                planned_file("new-large", 60, 3),
+                planned_file("mid", 50, 2),
+            ],
+            ..Plan::default()
+        };
+
+        let (fits, excluded) = plan.fit_budget(100, BudgetPriority::Newest);
+        assert_eq!(keys(&fits), ["new-large", "old-small"]);
+        assert_eq!(keys(&excluded), ["mid"]);
+
+        let (fits, excluded) = plan.fit_budget(100, BudgetPriority::Smallest);
+        assert_eq!(keys(&fits), ["old-small", "mid"]);
+        assert_eq!(keys(&excluded), ["new-large"]);
+
+        let (fits, _) = plan.fit_budget(100, BudgetPriority::Oldest);
+        assert_eq!(keys(&fits), ["old-small", "mid"]);
+
+        let (fits, _) = plan.fit_budget(100, BudgetPriority::Largest);
+        assert_eq!(keys(&fits), ["new-large", "old-small"]);
+    }
+}
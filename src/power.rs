@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::Path;
+
+const POWER_SUPPLY_DIRECTORY: &str = "/sys/class/power_supply";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Mains,
+    Battery,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerDecision {
+    Proceed,
+    Throttle,
+    Skip,
+}
+
+/// Skipping takes precedence over throttling, and an unknown power state never holds back a run
+pub fn decide(
+    state: PowerState,
+    skip_on_battery: bool,
+    throttle_on_battery: bool,
+) -> PowerDecision {
+    match state {
+        PowerState::Battery if skip_on_battery => PowerDecision::Skip,
+        PowerState::Battery if throttle_on_battery => PowerDecision::Throttle,
+        _ => PowerDecision::Proceed,
+    }
+}
+
+/// Reads the power status from sysfs, which is only available on Linux
+pub fn current_state() -> PowerState {
+    if cfg!(target_os = "linux") {
+        read_power_supplies(Path::new(POWER_SUPPLY_DIRECTORY))
+    } else {
+        PowerState::Unknown
+    }
+}
+
+fn read_power_supplies(directory: &Path) -> PowerState {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return PowerState::Unknown,
+    };
+
+    let mut state = PowerState::Unknown;
+    for entry in entries.flatten() {
+        let supply = entry.path();
+        let supply_type = read_attribute(&supply, "type");
+
+        match supply_type.as_deref() {
+            Some("Mains") | Some("USB")
+                if read_attribute(&supply, "online").as_deref() == Some("1") =>
+            {
+                return PowerState::Mains
+            }
+            Some("Battery")
+                if read_attribute(&supply, "status").as_deref() == Some("Discharging") =>
+            {
+                state = PowerState::Battery
+            }
+            _ => {}
+        }
+    }
+
+    state
+}
+
+fn read_attribute(supply: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(supply.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(directory: &Path, name: &str, attributes: &[(&str, &str)]) {
+        let supply = directory.join(name);
+        fs::create_dir_all(&supply).unwrap();
+        for (attribute, value) in attributes {
+            fs::write(supply.join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    fn temp_directory(name: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("backup-rs-power-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn battery_skips_or_throttles_when_asked() {
+        assert_eq!(decide(PowerState::Battery, true, true), PowerDecision::Skip);
+        assert_eq!(
+            decide(PowerState::Battery, false, true),
+            PowerDecision::Throttle
+        );
+        assert_eq!(
+            decide(PowerState::Battery, false, false),
+            PowerDecision::Proceed
+        );
+    }
+
+    #[test]
+    fn mains_and_unknown_power_proceed() {
+        for state in [PowerState::Mains, PowerState::Unknown] {
+            assert_eq!(decide(state, true, true), PowerDecision::Proceed);
+        }
+    }
+
+    #[test]
+    fn discharging_battery_is_read_from_sysfs() {
+        let directory = temp_directory("battery");
+        supply(&directory, "AC", &[("type", "Mains"), ("online", "0")]);
+        supply(
+            &directory,
+            "BAT0",
+            &[("type", "Battery"), ("status", "Discharging")],
+        );
+
+        assert_eq!(read_power_supplies(&directory), PowerState::Battery);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn online_mains_wins_over_a_battery() {
+        let directory = temp_directory("mains");
+        supply(&directory, "AC", &[("type", "Mains"), ("online", "1")]);
+        supply(
+            &directory,
+            "BAT0",
+            &[("type", "Battery"), ("status", "Discharging")],
+        );
+
+        assert_eq!(read_power_supplies(&directory), PowerState::Mains);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn missing_sysfs_is_unknown() {
+        assert_eq!(
+            read_power_supplies(&temp_directory("missing")),
+            PowerState::Unknown
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::summary::Summary;
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct Progress {
+    files_processed: usize,
+    files_uploaded: usize,
+    files_to_upload: usize,
+    files_failed: usize,
+    bytes_uploaded: u64,
+    bytes_to_upload: u64,
+    bytes_per_second: f64,
+    eta_seconds: Option<u64>,
+}
+
+/// Periodically replaces a JSON file with the progress of the run, so it can be monitored without a
+/// terminal. The totals come from planning the upload up front.
+pub struct ProgressFile {
+    path: PathBuf,
+    files_to_upload: usize,
+    bytes_to_upload: u64,
+    started: Instant,
+    last_written: Option<Instant>,
+}
+
+impl ProgressFile {
+    pub fn new(path: PathBuf, files_to_upload: usize, bytes_to_upload: u64) -> ProgressFile {
+        ProgressFile {
+            path,
+            files_to_upload,
+            bytes_to_upload,
+            started: Instant::now(),
+            last_written: None,
+        }
+    }
+
+    /// Writes the progress unless it was written less than the write interval ago
+    pub fn update(&mut self, summary: &Summary) -> io::Result<()> {
+        match self.last_written {
+            Some(last_written) if last_written.elapsed() < WRITE_INTERVAL => Ok(()),
+            _ => self.write(summary),
+        }
+    }
+
+    /// Written through a temporary file so readers never see a partial update
+    pub fn write(&mut self, summary: &Summary) -> io::Result<()> {
+        let bytes_uploaded = summary.bytes_uploaded;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let bytes_per_second = if elapsed > 0.0 {
+            bytes_uploaded as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta_seconds = (bytes_per_second > 0.0).then(|| {
+            (self.bytes_to_upload.saturating_sub(bytes_uploaded) as f64 / bytes_per_second) as u64
+        });
+
+        let progress = Progress {
+            files_processed: summary.candidates,
+            files_uploaded: summary.uploaded + summary.copied,
+            files_to_upload: self.files_to_upload,
+            files_failed: summary.failed.len(),
+            bytes_uploaded,
+            bytes_to_upload: self.bytes_to_upload,
+            bytes_per_second,
+            eta_seconds,
+        };
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&progress)?)?;
+        fs::rename(temp_path, &self.path)?;
+
+        self.last_written = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_is_written_at_most_once_per_interval() {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-progress-file-{}.json",
+            std::process::id()
+        ));
+        let mut progress_file = ProgressFile::new(path.clone(), 4, 100);
+        let summary = Summary {
+            candidates: 2,
+            uploaded: 1,
+            copied: 1,
+            bytes_uploaded: 50,
+            ..Summary::default()
+        };
+
+        progress_file.update(&summary).unwrap();
+        let progress: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(progress["files_processed"], 2);
+        assert_eq!(progress["files_uploaded"], 2);
+        assert_eq!(progress["files_to_upload"], 4);
+        assert_eq!(progress["bytes_uploaded"], 50);
+        assert_eq!(progress["bytes_to_upload"], 100);
+
+        fs::remove_file(&path).unwrap();
+        progress_file.update(&summary).unwrap();
+        assert!(!path.exists());
+    }
+}
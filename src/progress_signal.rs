@@ -0,0 +1,64 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Remembers that a progress report was requested until the traversal gets around to it, since the
+/// signal arrives at an arbitrary point during an upload
+#[derive(Debug, Clone, Default)]
+pub struct ProgressSignal {
+    requested: Arc<AtomicBool>,
+}
+
+impl ProgressSignal {
+    /// Starts listening for SIGUSR1
+    #[cfg(unix)]
+    pub fn listen() -> io::Result<ProgressSignal> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::user_defined1())?;
+        let progress_signal = ProgressSignal::default();
+        let requested = progress_signal.requested.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                requested.store(true, Ordering::Relaxed);
+            }
+        });
+
+        Ok(progress_signal)
+    }
+
+    /// There's no SIGUSR1 outside of unix, so a report is never requested
+    #[cfg(not(unix))]
+    pub fn listen() -> io::Result<ProgressSignal> {
+        Ok(ProgressSignal::default())
+    }
+
+    /// Whether a report was requested since the last call
+    pub fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signal_requests_a_single_report() {
+        let progress_signal = ProgressSignal::listen().unwrap();
+        assert!(!progress_signal.take());
+
+        unsafe { libc::raise(libc::SIGUSR1) };
+        let mut requested = false;
+        for _ in 0..100 {
+            if progress_signal.take() {
+                requested = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert!(requested);
+        assert!(!progress_signal.take());
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Spaces requests evenly so that no more than the configured number start within a second.
+/// Clones share their schedule, so every client and concurrent listing draws from the same budget.
+#[derive(Debug, Clone)]
+pub struct RequestLimiter {
+    interval: Duration,
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl RequestLimiter {
+    pub fn new(requests_per_second: u32) -> RequestLimiter {
+        RequestLimiter {
+            interval: Duration::from_secs(1) / requests_per_second.max(1),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Reserves the next free slot and waits until it arrives
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_are_spaced_by_the_interval() {
+        let limiter = RequestLimiter::new(20);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn clones_share_their_schedule() {
+        let limiter = RequestLimiter::new(20);
+        let clone = limiter.clone();
+        let start = Instant::now();
+        limiter.acquire().await;
+        clone.acquire().await;
+        limiter.acquire().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
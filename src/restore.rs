@@ -0,0 +1,538 @@
+use crate::archive;
+use crate::errors::{BackupError, BackupResult};
+use crate::keys;
+use crate::metadata::{apply_mode, sha256_from_s3_checksum, FileMetadata};
+use crate::options::{MetadataFilter, OnExisting, Options as CLIopts, VerifyMismatchAction};
+use crate::s3::{RemoteObject, S3Client};
+
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Existing local files which --on-existing kept
+    pub kept: usize,
+    pub mismatched: Vec<String>,
+    pub failed: Vec<String>,
+    /// Files which failed verification and were moved aside with a `.corrupt` extension
+    pub quarantined: Vec<PathBuf>,
+    /// Archived objects which are still being retrieved and weren't downloaded yet
+    pub pending: Vec<String>,
+}
+
+impl RestoreSummary {
+    pub fn succeeded(&self) -> bool {
+        self.mismatched.is_empty() && self.failed.is_empty() && self.pending.is_empty()
+    }
+}
+
+enum RestoreOutcome {
+    Restored,
+    Mismatched(Vec<&'static str>),
+}
+
+/// Keeps the objects whose stored metadata matches every filter, which takes a request per object
+/// since listings don't include metadata
+pub async fn select_by_metadata(
+    client: &S3Client,
+    objects: Vec<RemoteObject>,
+    filters: &[MetadataFilter],
+) -> BackupResult<Vec<RemoteObject>> {
+    let mut selected = Vec::new();
+
+    for object in objects {
+        let head = client.head_file(&object.key).await?;
+        if matches_filters(head.metadata(), filters) {
+            selected.push(object);
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Objects without any stored metadata never match
+fn matches_filters(metadata: Option<&HashMap<String, String>>, filters: &[MetadataFilter]) -> bool {
+    metadata.is_some_and(|metadata| {
+        filters
+            .iter()
+            .all(|filter| metadata.get(&filter.key) == Some(&filter.value))
+    })
+}
+
+pub async fn restore_objects(
+    client: &S3Client,
+    objects: Vec<RemoteObject>,
+    root: &Path,
+    args: &CLIopts,
+) -> RestoreSummary {
+    let mut summary = RestoreSummary::default();
+
+    // Only the objects which replace a local file are retrieved from the archive
+    let (objects, destinations): (Vec<_>, Vec<_>) =
+        select_replacements(objects, root, args, &mut summary)
+            .into_iter()
+            .unzip();
+    let (pending, failed) = archive::retrieve_all(client, &objects, args).await;
+    summary.failed.extend(failed);
+    summary.pending = pending;
+
+    let selected = objects.into_iter().zip(destinations).filter(|(object, _)| {
+        !summary.pending.contains(&object.key) && !summary.failed.contains(&object.key)
+    });
+
+    for (object, destination) in selected.collect::<Vec<_>>() {
+        let key = object.key;
+        info!("Restoring {}", key);
+
+        let mut outcome = restore_object(
+            client,
+            &key,
+            &destination,
+            args.verify,
+            args.durable_restore,
+        )
+        .await;
+        if let Ok(RestoreOutcome::Mismatched(fields)) = &outcome {
+            error!(
+                "Verification failed for {}: {} differs from the stored metadata",
+                key,
+                fields.join(", ")
+            );
+
+            if args.on_verify_mismatch == VerifyMismatchAction::Redownload {
+                info!("Downloading {} again", key);
+                outcome = restore_object(
+                    client,
+                    &key,
+                    &destination,
+                    args.verify,
+                    args.durable_restore,
+                )
+                .await;
+            }
+        }
+
+        match outcome {
+            Ok(RestoreOutcome::Restored) => summary.restored += 1,
+            Ok(RestoreOutcome::Mismatched(_))
+                if args.on_verify_mismatch == VerifyMismatchAction::Quarantine =>
+            {
+                match quarantine(&partial_path(&destination), &destination) {
+                    Ok(quarantined) => {
+                        warn!("Moved {} aside to {:?}", key, quarantined);
+                        summary.quarantined.push(quarantined);
+                    }
+                    Err(err) => {
+                        error!("Failed to quarantine {}: {}", key, err);
+                        summary.mismatched.push(key);
+                    }
+                }
+            }
+            Ok(RestoreOutcome::Mismatched(_)) => {
+                discard_partial(&destination);
+                summary.mismatched.push(key);
+            }
+            Err(err) => {
+                error!("Failed to restore {}: {}", key, err);
+                summary.failed.push(key);
+            }
+        }
+    }
+
+    summary
+}
+
+/// Pairs every object with the local file it restores to, leaving out the local files which are
+/// kept and the keys which would end up outside of the root
+fn select_replacements(
+    objects: Vec<RemoteObject>,
+    root: &Path,
+    args: &CLIopts,
+    summary: &mut RestoreSummary,
+) -> Vec<(RemoteObject, PathBuf)> {
+    let mut selected = Vec::new();
+    for object in objects {
+        let relative_key = match &args.strip_prefix {
+            Some(prefix) => keys::strip_key_prefix(&object.key, prefix).unwrap_or(&object.key),
+            None => &object.key,
+        };
+        let destination = match keys::key_to_path(root, relative_key) {
+            Some(destination) => destination,
+            None => {
+                error!("Refusing to restore {} outside of {:?}", object.key, root);
+                summary.failed.push(object.key);
+                continue;
+            }
+        };
+        if !should_replace(&destination, &object, args.on_existing) {
+            info!("Keeping the existing local file for {}", object.key);
+            summary.kept += 1;
+            continue;
+        }
+        selected.push((object, destination));
+    }
+    selected
+}
+
+/// Missing local files are always restored. The object's last modification is when it was
+/// uploaded, so a local file modified after that holds edits the backup doesn't have.
+fn should_replace(destination: &Path, object: &RemoteObject, policy: OnExisting) -> bool {
+    let local = match fs::metadata(destination) {
+        Ok(local) => local,
+        Err(_) => return true,
+    };
+
+    match policy {
+        OnExisting::Skip => false,
+        OnExisting::Overwrite => true,
+        OnExisting::OverwriteIfNewer => {
+            let local_modified = local
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|age| age.as_secs() as i64);
+            match (object.last_modified, local_modified) {
+                (Some(uploaded), Some(modified)) => uploaded > modified,
+                _ => false,
+            }
+        }
+        OnExisting::OverwriteIfDifferentSize => local.len() != object.size as u64,
+    }
+}
+
+/// Moves a download which failed verification aside instead of over the destination
+fn quarantine(partial: &Path, destination: &Path) -> std::io::Result<PathBuf> {
+    let mut file_name = destination.file_name().unwrap_or_default().to_owned();
+    file_name.push(".corrupt");
+
+    let quarantined = destination.with_file_name(file_name);
+    fs::rename(partial, &quarantined)?;
+    Ok(quarantined)
+}
+
+/// Objects are downloaded next to their destination, so an existing local file is only replaced
+/// once the download completed and was verified
+fn partial_path(destination: &Path) -> PathBuf {
+    let mut file_name = destination.file_name().unwrap_or_default().to_owned();
+    file_name.push(".partial");
+    destination.with_file_name(file_name)
+}
+
+fn discard_partial(destination: &Path) {
+    let partial = partial_path(destination);
+    match fs::remove_file(&partial) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            warn!(
+                "Unable to remove the partial download {:?}: {}",
+                partial, err
+            )
+        }
+        _ => {}
+    }
+}
+
+fn replace_destination(partial: &Path, destination: &Path, durable: bool) -> std::io::Result<()> {
+    fs::rename(partial, destination)?;
+    match destination.parent() {
+        Some(parent) if durable => sync_directory(parent),
+        _ => Ok(()),
+    }
+}
+
+/// A mismatched download is left at its partial path for the caller to quarantine or discard
+async fn restore_object(
+    client: &S3Client,
+    key: &str,
+    destination: &Path,
+    verify: bool,
+    durable: bool,
+) -> BackupResult<RestoreOutcome> {
+    let partial = partial_path(destination);
+    let outcome = match download(client, key, &partial, verify, durable).await {
+        Ok(RestoreOutcome::Restored) => replace_destination(&partial, destination, durable)
+            .map(|()| RestoreOutcome::Restored)
+            .map_err(BackupError::RestoreFailed),
+        outcome => outcome,
+    };
+
+    if outcome.is_err() {
+        discard_partial(destination);
+    }
+    outcome
+}
+
+async fn download(
+    client: &S3Client,
+    key: &str,
+    path: &Path,
+    verify: bool,
+    durable: bool,
+) -> BackupResult<RestoreOutcome> {
+    let object = client.download_file(key).await?;
+    let stored = object.metadata().and_then(FileMetadata::from_object);
+    let s3_checksum = object.checksum_sha256().map(str::to_owned);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(BackupError::RestoreFailed)?;
+    }
+
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(BackupError::RestoreFailed)?;
+    let mut body = object.body.into_async_read();
+    tokio::io::copy(&mut body, &mut file)
+        .await
+        .map_err(BackupError::RestoreFailed)?;
+
+    if durable {
+        file.sync_all().await.map_err(BackupError::RestoreFailed)?;
+    }
+
+    if let Some(mode) = stored.as_ref().and_then(|m| m.mode) {
+        apply_mode(path, mode).map_err(BackupError::RestoreFailed)?;
+    }
+
+    if !verify {
+        return Ok(RestoreOutcome::Restored);
+    }
+
+    verify_restored(key, path, stored, s3_checksum.as_deref())
+}
+
+/// Compares a restored file against the metadata and S3 checksum stored with its object
+fn verify_restored(
+    key: &str,
+    destination: &Path,
+    stored: Option<FileMetadata>,
+    s3_checksum: Option<&str>,
+) -> BackupResult<RestoreOutcome> {
+    if stored.is_none() && s3_checksum.is_none() {
+        warn!("No stored metadata or checksum to verify {} against", key);
+        return Ok(RestoreOutcome::Restored);
+    }
+
+    let actual = FileMetadata::from_file(destination).map_err(BackupError::RestoreFailed)?;
+    let mut mismatches = stored.map_or_else(Vec::new, |stored| stored.mismatches(&actual));
+    match s3_checksum.map(sha256_from_s3_checksum) {
+        Some(Some(expected)) if expected != actual.sha256 => mismatches.push("S3 checksum"),
+        Some(None) => debug!("Not comparing the multipart checksum of {}", key),
+        _ => {}
+    }
+    if mismatches.is_empty() {
+        Ok(RestoreOutcome::Restored)
+    } else {
+        Ok(RestoreOutcome::Mismatched(mismatches))
+    }
+}
+
+/// Persists the directory entry of a newly created file, so the file can't vanish after a power loss
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> std::io::Result<()> {
+    fs::File::open(directory)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, where the entry is persisted with the file
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-restore-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn corrupted_restore_reports_a_mismatch() {
+        let path = temp_file("corrupted", b"original content");
+        let stored = FileMetadata::from_file(&path).unwrap();
+        fs::write(&path, b"corrupted content").unwrap();
+
+        match verify_restored("key", &path, Some(stored), None).unwrap() {
+            RestoreOutcome::Mismatched(fields) => assert_eq!(fields, ["size", "sha256"]),
+            RestoreOutcome::Restored => panic!("the corruption went unnoticed"),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn intact_restore_is_verified() {
+        let path = temp_file("intact", b"original content");
+        let stored = FileMetadata::from_file(&path).unwrap();
+
+        assert!(matches!(
+            verify_restored("key", &path, Some(stored), None).unwrap(),
+            RestoreOutcome::Restored
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn quarantine_keeps_the_existing_file() {
+        let destination = temp_file("quarantine.txt", b"good local content");
+        fs::write(partial_path(&destination), b"corrupted content").unwrap();
+
+        let quarantined = quarantine(&partial_path(&destination), &destination).unwrap();
+        assert_eq!(
+            quarantined.file_name().unwrap().to_str().unwrap(),
+            format!(
+                "{}.corrupt",
+                destination.file_name().unwrap().to_str().unwrap()
+            )
+        );
+        assert!(!partial_path(&destination).exists());
+        assert_eq!(fs::read(&quarantined).unwrap(), b"corrupted content");
+        assert_eq!(fs::read(&destination).unwrap(), b"good local content");
+        fs::remove_file(quarantined).unwrap();
+        fs::remove_file(destination).unwrap();
+    }
+
+    #[test]
+    fn only_a_verified_download_replaces_the_existing_file() {
+        let destination = temp_file("replace.txt", b"good local content");
+        fs::write(partial_path(&destination), b"corrupted content").unwrap();
+
+        discard_partial(&destination);
+        assert!(!partial_path(&destination).exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"good local content");
+
+        fs::write(partial_path(&destination), b"restored content").unwrap();
+        replace_destination(&partial_path(&destination), &destination, true).unwrap();
+        assert!(!partial_path(&destination).exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"restored content");
+        fs::remove_file(destination).unwrap();
+    }
+
+    #[test]
+    fn objects_only_match_when_every_filter_does() {
+        let filters = [
+            "source-host=laptop".parse().unwrap(),
+            "Project=site".parse().unwrap(),
+        ];
+        let metadata = |host: &str| {
+            HashMap::from([
+                ("source-host".to_owned(), host.to_owned()),
+                ("project".to_owned(), "site".to_owned()),
+            ])
+        };
+
+        assert!(matches_filters(Some(&metadata("laptop")), &filters));
+        assert!(!matches_filters(Some(&metadata("desktop")), &filters));
+        assert!(!matches_filters(None, &filters));
+    }
+
+    #[test]
+    fn restored_directory_can_be_synced() {
+        assert!(sync_directory(&std::env::temp_dir()).is_ok());
+    }
+
+    #[test]
+    fn existing_files_are_replaced_per_the_policy() {
+        let path = temp_file("existing.txt", b"local");
+        let now = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let object = |size, last_modified| RemoteObject {
+            key: "existing.txt".to_owned(),
+            size,
+            last_modified: Some(last_modified),
+            e_tag: None,
+            storage_class: None,
+        };
+
+        assert!(!should_replace(&path, &object(5, now), OnExisting::Skip));
+        assert!(should_replace(
+            &path,
+            &object(5, now),
+            OnExisting::Overwrite
+        ));
+        assert!(should_replace(
+            &path,
+            &object(5, now + 60),
+            OnExisting::OverwriteIfNewer
+        ));
+        assert!(!should_replace(
+            &path,
+            &object(5, now - 60),
+            OnExisting::OverwriteIfNewer
+        ));
+        assert!(should_replace(
+            &path,
+            &object(6, now),
+            OnExisting::OverwriteIfDifferentSize
+        ));
+        assert!(!should_replace(
+            &path,
+            &object(5, now),
+            OnExisting::OverwriteIfDifferentSize
+        ));
+        assert!(should_replace(
+            &path.with_extension("missing"),
+            &object(5, now),
+            OnExisting::Skip
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_replaced_files_are_selected_for_retrieval() {
+        let root = std::env::temp_dir().join(format!(
+            "backup-rs-restore-selection-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("kept.txt"), b"local").unwrap();
+        let object = |key: &str| RemoteObject {
+            key: key.to_owned(),
+            size: 5,
+            last_modified: None,
+            e_tag: None,
+            storage_class: Some("GLACIER".to_owned()),
+        };
+        let args = CLIopts::from_iter([
+            "backup-rs",
+            "--bucket",
+            "bucket",
+            "--bucket-backup",
+            "backup",
+            "--on-existing",
+            "skip",
+            "/root",
+        ]);
+        let mut summary = RestoreSummary::default();
+
+        let selected = select_replacements(
+            vec![
+                object("kept.txt"),
+                object("new.txt"),
+                object("../outside.txt"),
+            ],
+            &root,
+            &args,
+            &mut summary,
+        );
+
+        assert_eq!(
+            selected
+                .iter()
+                .map(|(object, destination)| (object.key.as_str(), destination.clone()))
+                .collect::<Vec<_>>(),
+            [("new.txt", root.join("new.txt"))]
+        );
+        assert_eq!(summary.kept, 1);
+        assert_eq!(summary.failed, ["../outside.txt"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
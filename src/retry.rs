@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Caps the delay a provider can ask for, so a bogus header can't stall the run
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Limits retries both per file and across the whole run, so a single flaky file can't use up the
+/// retries which are meant for every other file
+#[derive(Debug)]
+pub struct RetryBudget {
+    per_file: u32,
+    remaining: u32,
+}
+
+impl RetryBudget {
+    pub fn new(per_file: u32, total: u32) -> RetryBudget {
+        RetryBudget {
+            per_file,
+            remaining: total,
+        }
+    }
+
+    /// Takes a retry from the run's budget for a file which has already been retried `retries` times
+    pub fn try_acquire(&mut self, retries: u32) -> bool {
+        if retries >= self.per_file || self.remaining == 0 {
+            return false;
+        }
+
+        self.remaining -= 1;
+        true
+    }
+}
+
+/// Exponential backoff before the given retry, starting at the first
+pub fn backoff(retry: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Waits as long as the provider asked for, falling back to exponential backoff
+pub fn delay(retry: u32, retry_after: Option<Duration>) -> Duration {
+    match retry_after {
+        Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+        None => backoff(retry),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_stop_at_the_per_file_limit() {
+        let mut budget = RetryBudget::new(2, 10);
+        assert!(budget.try_acquire(0));
+        assert!(budget.try_acquire(1));
+        assert!(!budget.try_acquire(2));
+    }
+
+    #[test]
+    fn retries_stop_once_the_run_budget_is_used_up() {
+        let mut budget = RetryBudget::new(5, 2);
+        assert!(budget.try_acquire(0));
+        assert!(budget.try_acquire(0));
+        assert!(!budget.try_acquire(0));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_its_maximum() {
+        assert_eq!(backoff(1), Duration::from_millis(500));
+        assert_eq!(backoff(2), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(4));
+        assert_eq!(backoff(20), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn requested_delay_takes_precedence_up_to_its_cap() {
+        assert_eq!(
+            delay(1, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+        assert_eq!(delay(1, Some(Duration::from_secs(3600))), MAX_RETRY_AFTER);
+        assert_eq!(delay(2, None), backoff(2));
+    }
+}
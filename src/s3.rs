@@ -1,14 +1,95 @@
+use crate::credentials;
 use crate::errors::{BackupError, BackupResult};
-use aws_sdk_s3::model::{ServerSideEncryption, StorageClass};
-use aws_sdk_s3::output::{ListObjectsV2Output, PutObjectOutput};
-use aws_sdk_s3::{types::{ByteStream}, Client, Region};
+use crate::keys;
+use crate::metadata::{self, FileMetadata};
+use crate::rate_limit::RequestLimiter;
+use crate::telemetry::{RequestKind, Telemetry};
+use aws_credential_types::time_source::TimeSource;
+use aws_sdk_s3::model::{
+    BucketLifecycleConfiguration, ChecksumAlgorithm, ChecksumMode, GlacierJobParameters,
+    IntelligentTieringAccessTier, IntelligentTieringConfiguration, IntelligentTieringStatus,
+    LifecycleRule, MetadataDirective, Object, RestoreRequest, ServerSideEncryption, StorageClass,
+    Tier, Tiering,
+};
+use aws_sdk_s3::output::{
+    CopyObjectOutput, DeleteObjectOutput, GetObjectOutput, HeadObjectOutput,
+    ListMultipartUploadsOutput, ListObjectsV2Output, ListPartsOutput,
+    PutBucketIntelligentTieringConfigurationOutput, PutBucketLifecycleConfigurationOutput,
+    PutObjectOutput, RestoreObjectOutput,
+};
+use aws_sdk_s3::types::{ByteStream, DateTime, SdkError};
+use aws_sdk_s3::{Client, Region};
+use aws_smithy_types::date_time::Format as DateTimeFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
+const TIERING_CONFIGURATION_ID: &str = "backup-rs";
+/// A key which isn't expected to exist, so requesting it yields a response without side effects
+const CLOCK_PROBE_KEY: &str = ".backup-rs-clock-probe";
+
+/// The details of an existing object which matter when deciding whether to upload a file again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteObject {
+    pub key: String,
+    pub size: i64,
+    pub last_modified: Option<i64>,
+    pub e_tag: Option<String>,
+    pub storage_class: Option<String>,
+}
+
+impl From<&Object> for RemoteObject {
+    fn from(object: &Object) -> RemoteObject {
+        RemoteObject {
+            key: object.key().expect("No filename found!").to_owned(),
+            size: object.size(),
+            last_modified: object.last_modified().map(|date| date.secs()),
+            e_tag: object.e_tag().map(str::to_owned),
+            storage_class: object
+                .storage_class()
+                .map(|class| class.as_str().to_owned()),
+        }
+    }
+}
+
+/// What a copy of an object onto itself has to carry over, as returned by a HEAD
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectAttributes {
+    pub metadata: HashMap<String, String>,
+    /// Left out of the response for STANDARD
+    pub storage_class: Option<StorageClass>,
+    pub sha256_checksum: bool,
+}
+
+impl ObjectAttributes {
+    pub fn from_head(head: &HeadObjectOutput) -> ObjectAttributes {
+        ObjectAttributes {
+            metadata: head.metadata().cloned().unwrap_or_default(),
+            storage_class: head.storage_class().cloned(),
+            sha256_checksum: head.checksum_sha256().is_some(),
+        }
+    }
+
+    /// Overlays our metadata, keeping any other user metadata such as the original path or birthtime
+    pub fn with_metadata(mut self, file_metadata: &FileMetadata) -> ObjectAttributes {
+        self.metadata.extend(file_metadata.to_object());
+        self
+    }
+}
+
+#[derive(Clone)]
 pub struct S3Client {
-    s3_client: Client,
+    /// Shared by all clones so a reconnect applies to all of them
+    s3_client: Arc<RwLock<Client>>,
+    region: Region,
     bucket: String,
     storage_class: StorageClass,
     encryption: ServerSideEncryption,
+    request_limiter: Option<RequestLimiter>,
+    telemetry: Arc<Telemetry>,
+    /// Whether uploads send the SHA-256 of their content for S3 to validate and store
+    sha256_checksums: bool,
 }
 
 impl S3Client {
@@ -19,8 +100,7 @@ impl S3Client {
         sse: &str,
     ) -> BackupResult<S3Client> {
         let region = Region::new(region);
-        let aws_config = aws_config::from_env().region(region).load().await;
-        let client = Client::new(&aws_config);
+        let client = connect(region.clone()).await;
 
         let storage_class = match StorageClass::from_str(storage_class) {
             Ok(class) => class,
@@ -33,36 +113,535 @@ impl S3Client {
         };
 
         Ok(S3Client {
-            s3_client: client,
+            s3_client: Arc::new(RwLock::new(client)),
+            region,
             bucket,
             storage_class,
             encryption: sse,
+            request_limiter: None,
+            telemetry: Arc::new(Telemetry::default()),
+            sha256_checksums: false,
         })
     }
 
-    pub async fn upload_file(&self, data: ByteStream, key: &str) -> BackupResult<PutObjectOutput> {
+    pub fn with_request_limit(self, request_limiter: Option<RequestLimiter>) -> S3Client {
+        S3Client {
+            request_limiter,
+            ..self
+        }
+    }
+
+    pub fn with_sha256_checksums(self, sha256_checksums: bool) -> S3Client {
+        S3Client {
+            sha256_checksums,
+            ..self
+        }
+    }
+
+    pub fn with_storage_class(self, storage_class: StorageClass) -> S3Client {
+        S3Client {
+            storage_class,
+            ..self
+        }
+    }
+
+    /// Replaces the SDK client, and with it the connection pool, which connection resets can leave
+    /// in a bad state
+    pub async fn reconnect(&self) {
+        let client = connect(self.region.clone()).await;
+        *self.s3_client.write().expect("S3 client lock poisoned") = client;
+    }
+
+    fn client(&self) -> Client {
         self.s3_client
+            .read()
+            .expect("S3 client lock poisoned")
+            .clone()
+    }
+
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    async fn before_request(&self, kind: RequestKind) {
+        self.telemetry.record_request(kind);
+        if let Some(request_limiter) = &self.request_limiter {
+            request_limiter.acquire().await;
+        }
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn storage_class(&self) -> &str {
+        self.storage_class.as_str()
+    }
+
+    pub async fn upload_file(
+        &self,
+        data: ByteStream,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> BackupResult<PutObjectOutput> {
+        let checksum = if self.sha256_checksums {
+            metadata::s3_checksum(&metadata)
+        } else {
+            None
+        };
+
+        self.before_request(RequestKind::Put).await;
+        self.client()
             .put_object()
             .bucket(&self.bucket)
-            .key(key.replace('\\', "/"))
+            .key(keys::to_key(key))
             .body(data)
+            .set_checksum_sha256(checksum)
+            .set_metadata(Some(metadata))
             .set_storage_class(Some(self.storage_class.to_owned()))
             .server_side_encryption(self.encryption.to_owned())
             .send()
             .await
-            .map_err(BackupError::UploadFailed)
+            .map_err(BackupError::from)
     }
 
+    /// Lists the bucket from a continuation token, or from the first key after `start_after` when
+    /// starting a new listing
     pub async fn fetch_existing_objects(
         &self,
         continuation_token: Option<String>,
+        start_after: Option<String>,
     ) -> BackupResult<ListObjectsV2Output> {
-        self.s3_client
+        self.before_request(RequestKind::List).await;
+        self.client()
             .list_objects_v2()
             .bucket(&self.bucket)
-            .set_continuation_token(continuation_token.or(None))
+            .set_continuation_token(continuation_token)
+            .set_start_after(start_after)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Lists multipart uploads which were started but never completed or aborted
+    pub async fn list_multipart_uploads(
+        &self,
+        key_marker: Option<String>,
+        upload_id_marker: Option<String>,
+    ) -> BackupResult<ListMultipartUploadsOutput> {
+        self.before_request(RequestKind::List).await;
+        self.client()
+            .list_multipart_uploads()
+            .bucket(&self.bucket)
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    pub async fn list_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number_marker: Option<String>,
+    ) -> BackupResult<ListPartsOutput> {
+        self.before_request(RequestKind::List).await;
+        self.client()
+            .list_parts()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .set_part_number_marker(part_number_marker)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    pub async fn list_objects(
+        &self,
+        prefix: Option<&str>,
+        delimiter: Option<&str>,
+        continuation_token: Option<String>,
+    ) -> BackupResult<ListObjectsV2Output> {
+        self.before_request(RequestKind::List).await;
+        self.client()
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .set_prefix(prefix.map(str::to_owned))
+            .set_delimiter(delimiter.map(str::to_owned))
+            .set_continuation_token(continuation_token)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Uploads a body of a known length which can't be read from disk, e.g. one streamed from another bucket
+    pub async fn upload_stream(
+        &self,
+        data: ByteStream,
+        key: &str,
+        content_length: i64,
+        metadata: Option<HashMap<String, String>>,
+    ) -> BackupResult<PutObjectOutput> {
+        self.before_request(RequestKind::Put).await;
+        self.client()
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(data)
+            .content_length(content_length)
+            .set_metadata(metadata)
+            .set_storage_class(Some(self.storage_class.to_owned()))
+            .server_side_encryption(self.encryption.to_owned())
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Replaces the metadata of an object by copying it onto itself, without transferring the content
+    /// again. The copy keeps the object's storage class and has S3 compute its SHA256 checksum again,
+    /// since a copy which replaces the metadata would otherwise lose both.
+    pub async fn update_metadata(
+        &self,
+        key: &str,
+        attributes: &ObjectAttributes,
+    ) -> BackupResult<CopyObjectOutput> {
+        self.before_request(RequestKind::Copy).await;
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .copy_source(format!("{}/{}", self.bucket, encode_key(key)))
+            .metadata_directive(MetadataDirective::Replace)
+            .set_metadata(Some(attributes.metadata.clone()))
+            .storage_class(
+                attributes
+                    .storage_class
+                    .clone()
+                    .unwrap_or(StorageClass::Standard),
+            )
+            .set_checksum_algorithm(
+                attributes
+                    .sha256_checksum
+                    .then_some(ChecksumAlgorithm::Sha256),
+            )
+            .server_side_encryption(self.encryption.to_owned())
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Creates an object from another one in the same bucket, replacing its metadata
+    pub async fn copy_file(
+        &self,
+        source_key: &str,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> BackupResult<CopyObjectOutput> {
+        self.before_request(RequestKind::Copy).await;
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .copy_source(format!("{}/{}", self.bucket, encode_key(source_key)))
+            .metadata_directive(MetadataDirective::Replace)
+            .set_metadata(Some(metadata))
+            .set_storage_class(Some(self.storage_class.to_owned()))
+            .server_side_encryption(self.encryption.to_owned())
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    pub async fn delete_file(&self, key: &str) -> BackupResult<DeleteObjectOutput> {
+        self.before_request(RequestKind::Other).await;
+        self.client()
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Copies an object from another bucket into this one with this client's storage class, keeping its metadata
+    pub async fn copy_from(
+        &self,
+        source_bucket: &str,
+        key: &str,
+    ) -> BackupResult<CopyObjectOutput> {
+        self.before_request(RequestKind::Copy).await;
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .copy_source(format!("{}/{}", source_bucket, encode_key(key)))
+            .metadata_directive(MetadataDirective::Copy)
+            .set_storage_class(Some(self.storage_class.to_owned()))
+            .server_side_encryption(self.encryption.to_owned())
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    pub async fn head_file(&self, key: &str) -> BackupResult<HeadObjectOutput> {
+        self.before_request(RequestKind::Head).await;
+        self.client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Reads the server's current time from the `Date` header of a request for a missing object.
+    /// Successful responses don't expose their headers, so an unexpected hit yields nothing.
+    pub async fn server_time(&self) -> Option<i64> {
+        self.before_request(RequestKind::Head).await;
+        let result = self
+            .client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(CLOCK_PROBE_KEY)
+            .send()
+            .await;
+
+        let headers = match &result {
+            Err(SdkError::ServiceError(context)) => context.raw().http().headers(),
+            Err(SdkError::ResponseError(context)) => context.raw().http().headers(),
+            _ => return None,
+        };
+
+        let date = headers.get("date")?.to_str().ok()?;
+        DateTime::from_str(date, DateTimeFormat::HttpDate)
+            .ok()
+            .map(|date| date.secs())
+    }
+
+    /// Requests a temporary copy of an archived object which stays available for the given number of days
+    pub async fn restore_archived(
+        &self,
+        key: &str,
+        days: i32,
+        tier: &str,
+    ) -> BackupResult<RestoreObjectOutput> {
+        let request = RestoreRequest::builder()
+            .days(days)
+            .glacier_job_parameters(
+                GlacierJobParameters::builder()
+                    .tier(Tier::from(tier))
+                    .build(),
+            )
+            .build();
+
+        self.before_request(RequestKind::Other).await;
+        self.client()
+            .restore_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .restore_request(request)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    pub async fn download_file(&self, key: &str) -> BackupResult<GetObjectOutput> {
+        self.before_request(RequestKind::Get).await;
+        self.client()
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// Downloads the inclusive byte range `start..=end` of an object
+    pub async fn download_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> BackupResult<GetObjectOutput> {
+        self.before_request(RequestKind::Get).await;
+        self.client()
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", start, end))
             .send()
             .await
-            .map_err(BackupError::FileFetchFailed)
+            .map_err(BackupError::from)
+    }
+
+    /// Moves objects into the archive access tiers once they haven't been accessed for the given number of days
+    pub async fn configure_intelligent_tiering(
+        &self,
+        archive_access_days: i32,
+        deep_archive_access_days: Option<i32>,
+    ) -> BackupResult<PutBucketIntelligentTieringConfigurationOutput> {
+        let configuration = tiering_configuration(archive_access_days, deep_archive_access_days);
+
+        self.before_request(RequestKind::Other).await;
+        self.client()
+            .put_bucket_intelligent_tiering_configuration()
+            .bucket(&self.bucket)
+            .id(TIERING_CONFIGURATION_ID)
+            .intelligent_tiering_configuration(configuration)
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+
+    /// A bucket without a lifecycle configuration has no rules
+    pub async fn lifecycle_rules(&self) -> BackupResult<Vec<LifecycleRule>> {
+        self.before_request(RequestKind::Other).await;
+        match self
+            .client()
+            .get_bucket_lifecycle_configuration()
+            .bucket(&self.bucket)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(output.rules().unwrap_or_default().to_vec()),
+            Err(SdkError::ServiceError(context))
+                if context.err().code() == Some("NoSuchLifecycleConfiguration") =>
+            {
+                Ok(Vec::new())
+            }
+            Err(err) => Err(BackupError::from(err)),
+        }
+    }
+
+    /// Replaces all lifecycle rules of the bucket
+    pub async fn put_lifecycle_rules(
+        &self,
+        rules: Vec<LifecycleRule>,
+    ) -> BackupResult<PutBucketLifecycleConfigurationOutput> {
+        self.before_request(RequestKind::Other).await;
+        self.client()
+            .put_bucket_lifecycle_configuration()
+            .bucket(&self.bucket)
+            .lifecycle_configuration(
+                BucketLifecycleConfiguration::builder()
+                    .set_rules(Some(rules))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(BackupError::from)
+    }
+}
+
+fn tiering_configuration(
+    archive_access_days: i32,
+    deep_archive_access_days: Option<i32>,
+) -> IntelligentTieringConfiguration {
+    let mut configuration = IntelligentTieringConfiguration::builder()
+        .id(TIERING_CONFIGURATION_ID)
+        .status(IntelligentTieringStatus::Enabled)
+        .tierings(
+            Tiering::builder()
+                .days(archive_access_days)
+                .access_tier(IntelligentTieringAccessTier::ArchiveAccess)
+                .build(),
+        );
+
+    if let Some(days) = deep_archive_access_days {
+        configuration = configuration.tierings(
+            Tiering::builder()
+                .days(days)
+                .access_tier(IntelligentTieringAccessTier::DeepArchiveAccess)
+                .build(),
+        );
+    }
+
+    configuration.build()
+}
+
+async fn connect(region: Region) -> Client {
+    let aws_config = aws_config::from_env()
+        .region(region)
+        .credentials_cache(credentials::refreshing_cache(TimeSource::default()))
+        .load()
+        .await;
+    Client::new(&aws_config)
+}
+
+/// URL-encodes a key the way S3 expects in copy sources and inventory reports, leaving the
+/// path separators intact
+pub fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(configuration: &IntelligentTieringConfiguration) -> Vec<(i32, &str)> {
+        configuration
+            .tierings()
+            .unwrap_or_default()
+            .iter()
+            .map(|tiering| {
+                let tier = tiering.access_tier().map_or("", |tier| tier.as_str());
+                (tiering.days(), tier)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tiering_configuration_enables_the_archive_access_tier() {
+        let configuration = tiering_configuration(90, None);
+        assert_eq!(configuration.id(), Some(TIERING_CONFIGURATION_ID));
+        assert_eq!(
+            configuration.status(),
+            Some(&IntelligentTieringStatus::Enabled)
+        );
+        assert_eq!(tiers(&configuration), [(90, "ARCHIVE_ACCESS")]);
+    }
+
+    #[test]
+    fn tiering_configuration_adds_the_deep_archive_access_tier() {
+        let configuration = tiering_configuration(90, Some(180));
+        assert_eq!(
+            tiers(&configuration),
+            [(90, "ARCHIVE_ACCESS"), (180, "DEEP_ARCHIVE_ACCESS")]
+        );
+    }
+
+    #[test]
+    fn attributes_keep_what_a_metadata_update_would_lose() {
+        let head = HeadObjectOutput::builder()
+            .metadata("original-path", "a ")
+            .metadata("sha256", "old")
+            .storage_class(StorageClass::Glacier)
+            .checksum_sha256("checksum")
+            .build();
+        let file_metadata = FileMetadata {
+            size: 1,
+            sha256: "new".to_owned(),
+            mode: None,
+        };
+
+        let attributes = ObjectAttributes::from_head(&head).with_metadata(&file_metadata);
+        assert_eq!(attributes.metadata["original-path"], "a ");
+        assert_eq!(attributes.metadata["sha256"], "new");
+        assert_eq!(attributes.storage_class, Some(StorageClass::Glacier));
+        assert!(attributes.sha256_checksum);
     }
 }
@@ -0,0 +1,88 @@
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Why a file or directory wasn't uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// Matched an --exclude pattern
+    Excluded,
+    /// Left out by the extension filters or --from-git
+    Filtered,
+    /// Its key couldn't be derived, e.g. due to --on-problematic-key skip or --strip-components
+    UnusableKey,
+    Empty,
+    Unchanged,
+    PermissionDenied,
+    /// A directory which was already walked through another path
+    AlreadyWalked,
+    /// Neither a regular file nor a directory, e.g. a socket or device
+    Special,
+}
+
+#[derive(Debug, Serialize)]
+struct SkippedEntry<'a> {
+    path: &'a str,
+    reason: SkipReason,
+}
+
+/// Writes one JSON object per skipped path, so large backups can be audited
+pub struct SkippedReport {
+    writer: BufWriter<File>,
+}
+
+impl SkippedReport {
+    pub fn create(path: &Path, append: bool) -> io::Result<SkippedReport> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+
+        Ok(SkippedReport {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, path: &Path, reason: SkipReason) -> io::Result<()> {
+        let path = path.to_string_lossy();
+        let entry = SkippedEntry {
+            path: &path,
+            reason,
+        };
+        writeln!(self.writer, "{}", serde_json::to_string(&entry)?)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn every_skip_is_a_json_line() {
+        let path =
+            std::env::temp_dir().join(format!("backup-rs-skipped-report-{}", std::process::id()));
+        let mut report = SkippedReport::create(&path, false).unwrap();
+        report
+            .record(Path::new("dir/a.tmp"), SkipReason::Excluded)
+            .unwrap();
+        report
+            .record(Path::new("link"), SkipReason::AlreadyWalked)
+            .unwrap();
+        report.finish().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"path\":\"dir/a.tmp\",\"reason\":\"excluded\"}\n{\"path\":\"link\",\"reason\":\"already-walked\"}\n"
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
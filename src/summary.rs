@@ -0,0 +1,294 @@
+use crate::telemetry::TelemetrySnapshot;
+use crate::throughput::Throughput;
+
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+const DAY: u64 = 24 * 60 * 60;
+/// The upper bound of every age bucket, with anything older counted in a final bucket
+const AGE_BUCKETS: [(&str, u64); 4] = [
+    ("day", DAY),
+    ("week", 7 * DAY),
+    ("month", 30 * DAY),
+    ("year", 365 * DAY),
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryTotals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// How many uploaded files were last modified within each of the age buckets
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AgeDistribution {
+    pub counts: [usize; AGE_BUCKETS.len() + 1],
+}
+
+impl AgeDistribution {
+    pub fn record(&mut self, age: Duration) {
+        let bucket = AGE_BUCKETS
+            .iter()
+            .position(|(_, limit)| age.as_secs() <= *limit)
+            .unwrap_or(AGE_BUCKETS.len());
+        self.counts[bucket] += 1;
+    }
+}
+
+/// Tallies what happened during an upload so it can be reported once the run completes
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Files which were considered for upload, whether or not they needed it
+    pub candidates: usize,
+    pub uploaded: usize,
+    pub skipped: usize,
+    pub metadata_updated: usize,
+    pub empty_skipped: usize,
+    /// Files which were copied from an identical file uploaded earlier in the run
+    pub copied: usize,
+    pub insecure_files: Vec<(String, &'static str)>,
+    pub failed: Vec<String>,
+    pub bytes_uploaded: u64,
+    pub throughput: Option<Throughput>,
+    /// Uploads grouped by the top-level directory they live in, when requested
+    pub per_directory: Option<BTreeMap<String, DirectoryTotals>>,
+    /// Uploads grouped by how long ago they were modified, when requested
+    pub age_distribution: Option<AgeDistribution>,
+    /// Objects in the bucket when the run started, grouped by their storage class, when requested
+    pub storage_classes: Option<BTreeMap<String, DirectoryTotals>>,
+}
+
+impl Summary {
+    pub fn succeeded(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Attributes an upload to the first segment of its path, with files in the root grouped as `.`
+    pub fn record_directory(&mut self, filename_segments: &[String], bytes: u64) {
+        if let Some(per_directory) = &mut self.per_directory {
+            let directory = match filename_segments {
+                [directory, _, ..] => directory.as_str(),
+                _ => ".",
+            };
+
+            let totals = per_directory.entry(directory.to_owned()).or_default();
+            totals.files += 1;
+            totals.bytes += bytes;
+        }
+    }
+
+    /// Files modified in the future, e.g. due to clock differences, count as modified just now
+    pub fn record_age(&mut self, modified: SystemTime) {
+        if let Some(age_distribution) = &mut self.age_distribution {
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            age_distribution.record(age);
+        }
+    }
+
+    /// Objects listed without a storage class are in `STANDARD`
+    pub fn record_storage_class(&mut self, storage_class: Option<&str>, bytes: u64) {
+        if let Some(storage_classes) = &mut self.storage_classes {
+            let totals = storage_classes
+                .entry(storage_class.unwrap_or("STANDARD").to_owned())
+                .or_default();
+            totals.files += 1;
+            totals.bytes += bytes;
+        }
+    }
+
+    pub fn log(&self, bucket: &str) {
+        info!(
+            "Uploaded {} new files to {} and skipped {} existing files",
+            self.uploaded, bucket, self.skipped
+        );
+
+        if self.empty_skipped > 0 {
+            info!("Skipped {} empty files", self.empty_skipped);
+        }
+
+        if self.copied > 0 {
+            info!(
+                "Copied {} files from identical files uploaded earlier",
+                self.copied
+            );
+        }
+
+        if self.metadata_updated > 0 {
+            info!(
+                "Updated the metadata of {} unchanged files",
+                self.metadata_updated
+            );
+        }
+
+        if let Some(throughput) = self.throughput {
+            info!(
+                "Uploaded {} at an average of {}/s and a peak of {}/s",
+                format_bytes(self.bytes_uploaded as f64),
+                format_bytes(throughput.average),
+                format_bytes(throughput.peak)
+            );
+        }
+
+        if let Some(per_directory) = &self.per_directory {
+            info!("Uploads per top-level directory:");
+            for (directory, totals) in per_directory {
+                info!(
+                    "  {}: {} files, {}",
+                    directory,
+                    totals.files,
+                    format_bytes(totals.bytes as f64)
+                );
+            }
+        }
+
+        if let Some(age_distribution) = &self.age_distribution {
+            info!("Uploads by time since their last modification:");
+            for ((period, _), count) in AGE_BUCKETS.iter().zip(age_distribution.counts) {
+                info!("  within a {}: {} files", period, count);
+            }
+            info!(
+                "  older than a {}: {} files",
+                AGE_BUCKETS[AGE_BUCKETS.len() - 1].0,
+                age_distribution.counts[AGE_BUCKETS.len()]
+            );
+        }
+
+        if let Some(storage_classes) = &self.storage_classes {
+            info!("Objects in {} per storage class:", bucket);
+            for (storage_class, totals) in storage_classes {
+                info!(
+                    "  {}: {} objects, {}",
+                    storage_class,
+                    totals.files,
+                    format_bytes(totals.bytes as f64)
+                );
+            }
+        }
+
+        if !self.insecure_files.is_empty() {
+            warn!(
+                "Found {} files with insecure permissions:",
+                self.insecure_files.len()
+            );
+            for (file, reason) in &self.insecure_files {
+                warn!("  {}: {}", file, reason);
+            }
+        }
+
+        if !self.failed.is_empty() {
+            error!("Failed to back up {} files:", self.failed.len());
+            for file in &self.failed {
+                error!("  {}", file);
+            }
+        }
+    }
+}
+
+pub fn log_telemetry(bucket: &str, telemetry: &TelemetrySnapshot) {
+    info!(
+        "Sent {} requests to {}: {} puts, {} lists, {} heads, {} gets, {} copies, {} other",
+        telemetry.requests(),
+        bucket,
+        telemetry.puts,
+        telemetry.lists,
+        telemetry.heads,
+        telemetry.gets,
+        telemetry.copies,
+        telemetry.other_requests
+    );
+
+    if telemetry.retries() > 0 {
+        info!(
+            "Retried {} uploads: {} throttled, {} timed out, {} server errors, {} other",
+            telemetry.retries(),
+            telemetry.throttling_retries,
+            telemetry.timeout_retries,
+            telemetry.server_error_retries,
+            telemetry.other_retries
+        );
+    }
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_are_formatted_in_binary_units() {
+        assert_eq!(format_bytes(512.0), "512.0 B");
+        assert_eq!(format_bytes(1536.0), "1.5 KiB");
+        assert_eq!(format_bytes(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0 GiB");
+    }
+
+    #[test]
+    fn uploads_are_grouped_by_their_top_level_directory() {
+        let mut summary = Summary {
+            per_directory: Some(BTreeMap::new()),
+            ..Summary::default()
+        };
+        let segments = |path: &str| path.split('/').map(str::to_owned).collect::<Vec<_>>();
+        summary.record_directory(&segments("photos/2023/a.jpg"), 10);
+        summary.record_directory(&segments("photos/b.jpg"), 5);
+        summary.record_directory(&segments("notes.txt"), 1);
+
+        let per_directory = summary.per_directory.unwrap();
+        assert_eq!(
+            per_directory["photos"],
+            DirectoryTotals {
+                files: 2,
+                bytes: 15
+            }
+        );
+        assert_eq!(per_directory["."], DirectoryTotals { files: 1, bytes: 1 });
+    }
+
+    #[test]
+    fn ages_fall_in_the_first_bucket_covering_them() {
+        let mut distribution = AgeDistribution::default();
+        for days in [0, 1, 2, 30, 400] {
+            distribution.record(Duration::from_secs(days * DAY));
+        }
+
+        assert_eq!(distribution.counts, [2, 1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn objects_without_a_storage_class_are_standard() {
+        let mut summary = Summary {
+            storage_classes: Some(BTreeMap::new()),
+            ..Summary::default()
+        };
+        summary.record_storage_class(None, 10);
+        summary.record_storage_class(Some("STANDARD"), 5);
+        summary.record_storage_class(Some("GLACIER"), 1);
+
+        let storage_classes = summary.storage_classes.unwrap();
+        assert_eq!(
+            storage_classes["STANDARD"],
+            DirectoryTotals {
+                files: 2,
+                bytes: 15
+            }
+        );
+        assert_eq!(
+            storage_classes["GLACIER"],
+            DirectoryTotals { files: 1, bytes: 1 }
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Put,
+    List,
+    Head,
+    Get,
+    Copy,
+    Other,
+}
+
+/// Why a request had to be retried, as far as can be told from the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryKind {
+    Throttling,
+    Timeout,
+    ServerError,
+    Other,
+}
+
+/// Counts the requests sent to a bucket and the retries they needed, shared by all clones of a client
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    puts: AtomicU64,
+    lists: AtomicU64,
+    heads: AtomicU64,
+    gets: AtomicU64,
+    copies: AtomicU64,
+    other_requests: AtomicU64,
+    throttling_retries: AtomicU64,
+    timeout_retries: AtomicU64,
+    server_error_retries: AtomicU64,
+    other_retries: AtomicU64,
+}
+
+/// The counters at a point in time, in the order they're reported
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TelemetrySnapshot {
+    pub puts: u64,
+    pub lists: u64,
+    pub heads: u64,
+    pub gets: u64,
+    pub copies: u64,
+    pub other_requests: u64,
+    pub throttling_retries: u64,
+    pub timeout_retries: u64,
+    pub server_error_retries: u64,
+    pub other_retries: u64,
+}
+
+impl TelemetrySnapshot {
+    pub fn requests(&self) -> u64 {
+        self.puts + self.lists + self.heads + self.gets + self.copies + self.other_requests
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.throttling_retries
+            + self.timeout_retries
+            + self.server_error_retries
+            + self.other_retries
+    }
+}
+
+impl Telemetry {
+    pub fn record_request(&self, kind: RequestKind) {
+        let counter = match kind {
+            RequestKind::Put => &self.puts,
+            RequestKind::List => &self.lists,
+            RequestKind::Head => &self.heads,
+            RequestKind::Get => &self.gets,
+            RequestKind::Copy => &self.copies,
+            RequestKind::Other => &self.other_requests,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, kind: RetryKind) {
+        let counter = match kind {
+            RetryKind::Throttling => &self.throttling_retries,
+            RetryKind::Timeout => &self.timeout_retries,
+            RetryKind::ServerError => &self.server_error_retries,
+            RetryKind::Other => &self.other_retries,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TelemetrySnapshot {
+        TelemetrySnapshot {
+            puts: self.puts.load(Ordering::Relaxed),
+            lists: self.lists.load(Ordering::Relaxed),
+            heads: self.heads.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            copies: self.copies.load(Ordering::Relaxed),
+            other_requests: self.other_requests.load(Ordering::Relaxed),
+            throttling_retries: self.throttling_retries.load(Ordering::Relaxed),
+            timeout_retries: self.timeout_retries.load(Ordering::Relaxed),
+            server_error_retries: self.server_error_retries.load(Ordering::Relaxed),
+            other_retries: self.other_retries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_and_retries_are_counted_by_kind() {
+        let telemetry = Telemetry::default();
+        telemetry.record_request(RequestKind::Put);
+        telemetry.record_request(RequestKind::Put);
+        telemetry.record_request(RequestKind::List);
+        telemetry.record_retry(RetryKind::Throttling);
+
+        let snapshot = telemetry.snapshot();
+        assert_eq!(snapshot.puts, 2);
+        assert_eq!(snapshot.lists, 1);
+        assert_eq!(snapshot.requests(), 3);
+        assert_eq!(snapshot.throttling_retries, 1);
+        assert_eq!(snapshot.retries(), 1);
+    }
+}
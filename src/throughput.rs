@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+/// Uploads which finish faster than this are too short to tell their rate
+const MIN_UPLOAD_DURATION: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    /// Bytes per second over the whole run
+    pub average: f64,
+    /// Bytes per second of the fastest upload
+    pub peak: f64,
+}
+
+/// Times the run and every upload in it, so throughput can be derived once the run completes.
+/// Files are uploaded one at a time, so the rate of a single upload is the rate of the run while
+/// it lasted.
+#[derive(Debug)]
+pub struct ThroughputTracker {
+    started: Instant,
+    peak: f64,
+}
+
+impl ThroughputTracker {
+    pub fn start() -> ThroughputTracker {
+        ThroughputTracker {
+            started: Instant::now(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn record_upload(&mut self, bytes: u64, duration: Duration) {
+        if duration >= MIN_UPLOAD_DURATION {
+            self.peak = self.peak.max(bytes as f64 / duration.as_secs_f64());
+        }
+    }
+
+    pub fn finish(&self, total: u64) -> Throughput {
+        compute(total, self.started.elapsed(), self.peak)
+    }
+}
+
+/// The peak is never below the average, which covers runs whose uploads were all too short to time
+pub fn compute(total: u64, elapsed: Duration, peak: f64) -> Throughput {
+    let average = if elapsed.is_zero() {
+        0.0
+    } else {
+        total as f64 / elapsed.as_secs_f64()
+    };
+
+    Throughput {
+        average,
+        peak: peak.max(average),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peak_is_the_fastest_upload() {
+        let mut tracker = ThroughputTracker::start();
+        tracker.record_upload(100, Duration::from_secs(1));
+        tracker.record_upload(600, Duration::from_secs(2));
+        tracker.record_upload(1000, Duration::from_secs(10));
+
+        assert_eq!(tracker.peak, 300.0);
+    }
+
+    #[test]
+    fn short_uploads_arent_timed() {
+        let mut tracker = ThroughputTracker::start();
+        tracker.record_upload(1_000_000, Duration::from_millis(1));
+
+        assert_eq!(tracker.peak, 0.0);
+    }
+
+    #[test]
+    fn peak_is_never_below_the_average() {
+        let throughput = compute(1000, Duration::from_millis(500), 0.0);
+        assert_eq!(throughput.average, 2000.0);
+        assert_eq!(throughput.peak, 2000.0);
+    }
+
+    #[test]
+    fn run_without_time_has_no_throughput() {
+        let throughput = compute(0, Duration::ZERO, 0.0);
+        assert_eq!(throughput.average, 0.0);
+        assert_eq!(throughput.peak, 0.0);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::errors::{BackupError, BackupResult};
+use crate::s3::S3Client;
+
+use std::future::Future;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Compares an uploaded object against the local file one ranged download at a time, so only a
+/// single chunk of either is held in memory. Returns the offset of the first chunk which differs,
+/// or of the end of the shorter one when the object and the file differ in length.
+pub async fn first_mismatch(
+    client: &S3Client,
+    key: &str,
+    path: &Path,
+    chunk_size: u64,
+) -> BackupResult<Option<u64>> {
+    let remote_length = client.head_file(key).await?.content_length().max(0) as u64;
+
+    compare(path, remote_length, chunk_size, |start, end| async move {
+        let object = client.download_range(key, start, end).await?;
+        let mut remote = Vec::with_capacity((end - start + 1) as usize);
+        object
+            .body
+            .into_async_read()
+            .read_to_end(&mut remote)
+            .await
+            .map_err(BackupError::VerifyFailed)?;
+        Ok(remote)
+    })
+    .await
+}
+
+async fn compare<F, Fut>(
+    path: &Path,
+    remote_length: u64,
+    chunk_size: u64,
+    mut fetch_range: F,
+) -> BackupResult<Option<u64>>
+where
+    F: FnMut(u64, u64) -> Fut,
+    Fut: Future<Output = BackupResult<Vec<u8>>>,
+{
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(BackupError::VerifyFailed)?;
+    let local_length = file
+        .metadata()
+        .await
+        .map_err(BackupError::VerifyFailed)?
+        .len();
+    let length = local_length.min(remote_length);
+
+    let mut offset = 0;
+    while offset < length {
+        let end = (offset + chunk_size.max(1)).min(length) - 1;
+
+        let mut local = Vec::with_capacity((end - offset + 1) as usize);
+        (&mut file)
+            .take(end - offset + 1)
+            .read_to_end(&mut local)
+            .await
+            .map_err(BackupError::VerifyFailed)?;
+
+        if local != fetch_range(offset, end).await? {
+            return Ok(Some(offset));
+        }
+
+        offset = end + 1;
+    }
+
+    if local_length != remote_length {
+        return Ok(Some(length));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    async fn compare_with(local: &[u8], remote: &[u8]) -> Option<u64> {
+        let path = std::env::temp_dir().join(format!(
+            "backup-rs-verify-{}-{}",
+            local.len(),
+            std::process::id()
+        ));
+        fs::write(&path, local).unwrap();
+
+        let mismatch = compare(&path, remote.len() as u64, 4, |start, end| async move {
+            Ok(remote[start as usize..=end as usize].to_vec())
+        })
+        .await
+        .unwrap();
+        fs::remove_file(path).unwrap();
+        mismatch
+    }
+
+    #[tokio::test]
+    async fn identical_object_has_no_mismatch() {
+        assert_eq!(compare_with(b"0123456789", b"0123456789").await, None);
+    }
+
+    #[tokio::test]
+    async fn mismatch_reports_the_chunk_which_differs() {
+        assert_eq!(compare_with(b"01234567890", b"01234X67890").await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn longer_or_shorter_object_is_a_mismatch() {
+        assert_eq!(compare_with(b"012345", b"0123456789").await, Some(6));
+        assert_eq!(compare_with(b"0123456789ab", b"01234567").await, Some(8));
+    }
+}